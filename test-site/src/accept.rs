@@ -0,0 +1,105 @@
+//! `Accept` negotiation, see RFC 9110 section 12.5.1
+use crate::encoding::parse_qvalue;
+use crate::parse::Headers;
+
+/// How closely a media range matches a type, more specific ranges take precedence over less specific ones.
+#[inline]
+#[must_use]
+fn specificity(range: &[u8], mime: &[u8]) -> Option<u8> {
+    let (kind, _) = mime.split_at(mime.iter().position(|byte| *byte == b'/')?);
+    match range {
+        b"*/*" => Some(0),
+        _ if range.len() == kind.len() + 2 && range.ends_with(b"/*") => {
+            range[..kind.len()].eq_ignore_ascii_case(kind).then_some(1)
+        },
+        _ => range.eq_ignore_ascii_case(mime).then_some(2)
+    }
+}
+
+/// The most specific media range matching `mime` with its weight, whether the client sent any ranges at all.
+fn best(headers: &Headers, mime: &str) -> (Option<(u8, u16)>, bool) {
+    let mut best: Option<(u8, u16)> = None;
+    let mut any = false;
+
+    let elements = headers.get_all("accept")
+        .flat_map(|value| value.split(|byte| *byte == b','))
+        .map(<[u8]>::trim_ascii)
+        .filter(|element| !element.is_empty());
+
+    for element in elements {
+        any = true;
+        let mut params = element.split(|byte| *byte == b';').map(<[u8]>::trim_ascii);
+        let range = params.next().unwrap_or_default();
+        let q = params
+            .find_map(|param| param.strip_prefix(b"q=").or_else(|| param.strip_prefix(b"Q=")))
+            .map_or(Some(1000), parse_qvalue);
+        let (Some(q), Some(specificity)) = (q, specificity(range, mime.as_bytes())) else { continue };
+
+        if best.is_none_or(|(best, _)| specificity > best) {
+            best = Some((specificity, q));
+        }
+    }
+
+    (best, any)
+}
+
+/// The weight in thousandths the client gives `mime`, taken from the most specific media range which matches it.
+///
+/// Without an `Accept` header every type is acceptable with the same weight.
+#[must_use]
+pub fn quality(headers: &Headers, mime: &str) -> u16 {
+    match best(headers, mime) {
+        (Some((_, q)), _) => q,
+        (None, true) => 0,
+        (None, false) => 1000
+    }
+}
+
+/// Whether the client asked for `mime` by name or by its type (`text/*`), rather than accepting it through `*/*`.
+#[inline]
+#[must_use]
+pub fn explicitly(headers: &Headers, mime: &str) -> bool {
+    matches!(best(headers, mime).0, Some((1.., 1..)))
+}
+
+/// Whether the client would rather have `mime` than `over`, ties favour `over`.
+#[inline]
+#[must_use]
+pub fn prefers(headers: &Headers, mime: &str, over: &str) -> bool {
+    quality(headers, mime) > quality(headers, over)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(raw: &str) -> Headers<'_> {
+        let mut headers = Headers::new();
+        headers.push(b"Accept", raw.as_bytes());
+        headers
+    }
+
+    #[test]
+    fn media_ranges() {
+        let browser = accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8");
+        assert_eq!(quality(&browser, "text/html"), 1000);
+        assert_eq!(quality(&browser, "application/json"), 800);
+        assert!(explicitly(&browser, "text/html"));
+        assert!(!explicitly(&browser, "application/json"));
+        assert!(!prefers(&browser, "application/json", "text/html"));
+
+        let fetch = accept("application/json");
+        assert_eq!(quality(&fetch, "text/html"), 0);
+        assert!(prefers(&fetch, "application/json", "text/html"));
+
+        let ranged = accept("text/*;q=0.5, text/plain, */*;q=0.1");
+        assert_eq!(quality(&ranged, "text/plain"), 1000);
+        assert_eq!(quality(&ranged, "text/css"), 500);
+        assert_eq!(quality(&ranged, "image/png"), 100);
+
+        assert_eq!(quality(&Headers::new(), "application/json"), 1000);
+        assert!(!explicitly(&Headers::new(), "text/html"));
+        assert!(!explicitly(&accept("text/html;q=0"), "text/html"));
+        assert!(!prefers(&Headers::new(), "application/json", "text/html"));
+    }
+}
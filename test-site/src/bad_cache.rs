@@ -0,0 +1,109 @@
+//! Paths recently found not to exist, with the `bad-cache` feature, so that repeated requests for them skip the
+//! filesystem.
+//!
+//! Paths are kept decoded and relative to the dist directory, as they are once we have checked them. They are forgotten
+//! after the configured time to live, so that files written after they were first requested (such as by a build
+//! finishing late) are served, and with the `reload` feature as soon as the watcher sees them created.
+use crate::conf::BadCacheConf;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+pub struct BadCache {
+    /// When each path was found missing, `None` if the cache is disabled
+    paths: Option<Mutex<LruCache<PathBuf, Instant>>>,
+    ttl: Duration
+}
+
+impl BadCache {
+    #[must_use]
+    pub fn new(conf: &BadCacheConf) -> Self {
+        Self {
+            paths: NonZeroUsize::new(conf.capacity).map(|capacity| Mutex::new(LruCache::new(capacity))),
+            ttl: conf.ttl()
+        }
+    }
+
+    /// Whether `path` was recently found missing, forgetting it if that was too long ago.
+    pub fn contains(&self, path: &Path) -> bool {
+        let Some(paths) = &self.paths else { return false };
+        let mut paths = paths.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match paths.get(path) {
+            Some(missing_since) if missing_since.elapsed() < self.ttl => true,
+            Some(_) => {
+                trace!("{} expired from the `bad-cache`", path.display());
+                paths.pop(path);
+                false
+            },
+            None => false
+        }
+    }
+
+    /// Remember that `path` does not exist.
+    pub fn insert(&self, path: PathBuf) {
+        if let Some(paths) = &self.paths {
+            paths.lock().unwrap_or_else(PoisonError::into_inner).put(path, Instant::now());
+        }
+    }
+
+    /// Forget the paths within any of `changed`, which were created, removed or renamed. Everything is forgotten if
+    /// `rescan`, as we do not know what changed. Returns how many paths were forgotten.
+    #[cfg(feature = "reload")]
+    pub fn invalidate(&self, changed: &[&Path], rescan: bool) -> usize {
+        let Some(paths) = &self.paths else { return 0 };
+        let mut paths = paths.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if rescan {
+            let forgotten = paths.len();
+            paths.clear();
+            return forgotten;
+        }
+        let stale = paths.iter()
+            .map(|(path, _)| path)
+            .filter(|path| changed.iter().any(|changed| path.starts_with(changed)))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in &stale {
+            paths.pop(path);
+        }
+        stale.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires() {
+        let cache = BadCache::new(&BadCacheConf { capacity: 2, ttl: 60 });
+        cache.insert(PathBuf::from("late.js"));
+        cache.insert(PathBuf::from("assets/my file.css"));
+        assert!(cache.contains(Path::new("late.js")));
+        assert!(cache.contains(Path::new("assets/my file.css")));
+
+        // the least recently requested is forgotten first
+        cache.insert(PathBuf::from("third.js"));
+        assert!(!cache.contains(Path::new("late.js")));
+
+        let expired = BadCache::new(&BadCacheConf { capacity: 2, ttl: 0 });
+        expired.insert(PathBuf::from("late.js"));
+        assert!(!expired.contains(Path::new("late.js")));
+
+        let disabled = BadCache::new(&BadCacheConf { capacity: 0, ttl: 60 });
+        disabled.insert(PathBuf::from("late.js"));
+        assert!(!disabled.contains(Path::new("late.js")));
+
+        #[cfg(feature = "reload")] {
+            // a directory being created may bring any path within it into existence
+            assert_eq!(cache.invalidate(&[Path::new("assets")], false), 1);
+            assert!(!cache.contains(Path::new("assets/my file.css")));
+            assert!(cache.contains(Path::new("third.js")));
+            assert_eq!(cache.invalidate(&[], true), 1);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Conditional requests, see RFC 9110 section 13
+use crate::lazy_file::Contents;
+use crate::parse::Headers;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Strip the weakness indicator of an entity tag, for the weak comparison.
+#[inline]
+#[must_use]
+fn strip_weak(tag: &[u8]) -> &[u8] {
+    tag.strip_prefix(b"W/").unwrap_or(tag)
+}
+
+/// Whether any of the comma separated entity tags in `header` match `etag` (weak comparison), or `header` is `*`.
+#[inline]
+#[must_use]
+fn etag_matches(header: &[u8], etag: &str) -> bool {
+    header.split(|byte| *byte == b',')
+        .map(<[u8]>::trim_ascii)
+        .any(|tag| tag == b"*" || strip_weak(tag) == strip_weak(etag.as_bytes()))
+}
+
+/// HTTP-dates only have second precision, so modification times must be truncated before comparison.
+#[inline]
+#[must_use]
+pub fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+#[inline]
+#[must_use]
+pub fn parse_date(raw: &[u8]) -> Option<SystemTime> {
+    core::str::from_utf8(raw).ok().and_then(|date| httpdate::parse_http_date(date).ok())
+}
+
+/// Whether a `GET` or `HEAD` request for `contents` should be answered with `304 Not Modified`.
+///
+/// `If-Modified-Since` is only considered in the absence of `If-None-Match`, as entity tags are the more precise
+/// validator.
+#[must_use]
+pub fn not_modified(headers: &Headers, contents: &Contents) -> bool {
+    let mut none_match = headers.get_all("if-none-match").peekable();
+    if none_match.peek().is_some() {
+        return none_match.any(|header| etag_matches(header, contents.etag()));
+    }
+
+    match (headers.get("if-modified-since").and_then(parse_date), contents.modified()) {
+        (Some(since), Some(modified)) => secs(modified) <= secs(since),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn headers<'a>(raw: &[(&'a str, &'a str)]) -> Headers<'a> {
+        let mut headers = Headers::new();
+        for (name, value) in raw {
+            headers.push(name.as_bytes(), value.as_bytes());
+        }
+        headers
+    }
+
+    #[test]
+    fn if_none_match() {
+        let contents = Contents::new(b"hello".to_vec(), None);
+        let etag = contents.etag();
+
+        assert!(!not_modified(&headers(&[]), &contents));
+        assert!(not_modified(&headers(&[("If-None-Match", "*")]), &contents));
+        assert!(!not_modified(&headers(&[("If-None-Match", "\"other\"")]), &contents));
+
+        let listed = format!("\"other\", W/{etag}");
+        assert!(not_modified(&headers(&[("if-none-match", &listed)]), &contents));
+        assert!(not_modified(&headers(&[("If-None-Match", "\"other\""), ("If-None-Match", etag)]), &contents));
+
+        let changed = Contents::new(b"hello!".to_vec(), None);
+        assert!(!not_modified(&headers(&[("If-None-Match", etag)]), &changed));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let contents = Contents::new(b"hello".to_vec(), Some(modified));
+
+        let exact = contents.last_modified().unwrap();
+        assert!(not_modified(&headers(&[("If-Modified-Since", exact)]), &contents));
+        assert!(not_modified(&headers(&[("If-Modified-Since", "Tue, 14 Nov 2023 22:13:21 GMT")]), &contents));
+        assert!(!not_modified(&headers(&[("If-Modified-Since", "Tue, 14 Nov 2023 22:13:19 GMT")]), &contents));
+        assert!(!not_modified(&headers(&[("If-Modified-Since", "yesterday")]), &contents));
+
+        // If-None-Match takes precedence
+        assert!(!not_modified(&headers(&[("If-None-Match", "\"other\""), ("If-Modified-Since", exact)]), &contents));
+    }
+}
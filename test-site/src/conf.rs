@@ -0,0 +1,960 @@
+//! Runtime server configuration
+//!
+//! Configuration is gathered from three sources and merged with the following precedence (highest first):
+//!
+//! 1. Command line flags
+//! 2. Environment variables
+//! 3. The TOML config file (`--config`, `TEST_CONFIG`, or `test-site.toml` in the working directory)
+//!
+//! Anything left unset falls back to the defaults. The merged configuration is validated before the server starts so
+//! a bad path or port is reported up front rather than as a stream of `404`s.
+use crate::glob::Glob;
+use crate::rules::Preset;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fmt, fs, io};
+use tracing::{instrument, info, warn, Level};
+
+const DEFAULT_CONFIG: &str = "test-site.toml";
+const DEFAULT_PORT: u16 = 6969;
+const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const DEFAULT_INDEX: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/index.html");
+const DEFAULT_DIST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/dist");
+const DEFAULT_CERT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/.certs");
+const MIN_HEADER_SIZE: usize = 1024;
+
+const USAGE: &str = "\
+Usage: test-site [OPTIONS]
+
+Options:
+  -p, --port <PORT>        Port to listen on                      [env: TEST_PORT]
+      --host <ADDR>        Address to listen on                   [env: TEST_HOST]
+  -i, --index <FILE>       Index file served at `/`               [env: TEST_SITE_PATH]
+  -d, --dist <DIR>         Directory of distributed files         [env: TEST_DIST_PATH]
+  -t, --threads <N>        Number of worker threads               [env: SERVER_THREADS]
+  -c, --config <FILE>      TOML configuration file                [env: TEST_CONFIG]
+      --tls                Serve HTTPS, with a generated certificate unless configured otherwise
+  -h, --help               Print this message
+";
+
+macro_rules! invalid {
+    ($($arg:tt)*) => {
+        io::Error::new(io::ErrorKind::InvalidInput, format!($($arg)*))
+    };
+}
+
+/// Connection settings, these may only be set in the config file under `[http]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConf {
+    /// Seconds an idle connection is kept open waiting for its next request
+    pub keep_alive_timeout: u64,
+    /// Requests served on a single connection before it is closed, `1` disables keep-alive
+    pub max_requests: usize,
+    /// Maximum size in bytes of a request's line and headers
+    pub max_header_size: usize,
+    /// Maximum size in bytes of a request's body, which is never read but may be sent along with one we do serve
+    pub max_body_size: u64,
+    /// Seconds a client has to send a complete request head once it has started sending one
+    pub read_timeout: u64,
+    /// Streams an HTTP/2 client may have open at once on a single connection
+    pub max_concurrent_streams: u32
+}
+
+impl Default for HttpConf {
+    fn default() -> Self {
+        Self {
+            keep_alive_timeout: 5,
+            max_requests: 1000,
+            max_header_size: 16 * 1024,
+            max_body_size: 64 * 1024,
+            read_timeout: 10,
+            max_concurrent_streams: 100
+        }
+    }
+}
+
+impl HttpConf {
+    #[inline]
+    #[must_use]
+    pub const fn keep_alive_timeout(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_timeout)
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout)
+    }
+}
+
+/// HTTPS settings, TLS is enabled by the presence of the `[tls]` table or `--tls`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConf {
+    /// PEM encoded certificate chain, if unset with `key` a certificate is generated
+    pub cert: Option<PathBuf>,
+    /// PEM encoded private key of `cert`
+    pub key: Option<PathBuf>,
+    /// Additional hostnames and addresses the generated certificate is valid for, besides localhost
+    pub names: Vec<String>,
+    /// Where the generated CA and certificate are kept between runs
+    pub cert_dir: Option<PathBuf>,
+    /// Port of a plain HTTP listener which redirects every request to HTTPS
+    pub redirect_port: Option<u16>
+}
+
+impl TlsConf {
+    #[inline]
+    #[must_use]
+    pub fn cert_dir(&self) -> &Path {
+        self.cert_dir.as_deref().unwrap_or(Path::new(DEFAULT_CERT_DIR))
+    }
+}
+
+/// The characters path segments may contain once decoded, whatever the charset `.`, `..`, either path separator and
+/// control characters (including NUL) are always rejected.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Charset {
+    /// Only `[A-Za-z0-9_.-]`
+    Strict,
+    /// Printable ASCII
+    Ascii,
+    /// Any UTF-8
+    #[default]
+    Utf8
+}
+
+impl Charset {
+    #[inline]
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Strict => "strict",
+            Self::Ascii => "ascii",
+            Self::Utf8 => "utf8"
+        }
+    }
+}
+
+/// Which request paths map onto files, under `[paths]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConf {
+    /// The characters segments may contain
+    pub charset: Charset,
+    /// Characters to reject on top of those `charset` rejects
+    pub deny: String,
+    /// Refuse to serve files which resolve outside of the dist directory, through symlinks or otherwise
+    pub confine: bool,
+    /// Directories outside of the dist directory which symlinks may still point into when `confine` is set
+    pub allow_symlinks: Vec<PathBuf>,
+    /// The files served for a directory, the first which exists is used
+    pub index_files: Vec<String>,
+    /// List the contents of directories without an index file
+    pub listing: bool
+}
+
+impl Default for PathsConf {
+    fn default() -> Self {
+        Self {
+            charset: Charset::default(),
+            deny: String::new(),
+            confine: false,
+            allow_symlinks: Vec::new(),
+            index_files: vec!["index.html".to_owned()],
+            listing: false
+        }
+    }
+}
+
+/// Remembering paths which do not exist, under `[bad_cache]`. Requires the `bad-cache` feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BadCacheConf {
+    /// Paths remembered per dist directory, the least recently requested are forgotten first. `0` disables the cache
+    pub capacity: usize,
+    /// Seconds a path is remembered for, so that files created later are found without the `reload` feature
+    pub ttl: u64
+}
+
+impl Default for BadCacheConf {
+    fn default() -> Self {
+        Self { capacity: 64, ttl: 2 }
+    }
+}
+
+impl BadCacheConf {
+    #[inline]
+    #[must_use]
+    pub const fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+}
+
+/// History API fallback for single page apps, enabled by the presence of the `[fallback]` table.
+///
+/// Paths which do not exist are answered with the index file when they look like navigations, no extension in their
+/// last segment and `text/html` named in `Accept`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackConf {
+    /// Paths which may fall back to the index, all of them by default
+    pub include: Vec<Glob>,
+    /// Paths which never fall back, even if included
+    pub exclude: Vec<Glob>
+}
+
+impl Default for FallbackConf {
+    fn default() -> Self {
+        Self {
+            include: vec![Glob::new("/**").unwrap(/* infallible, the pattern is not empty */)],
+            exclude: Vec::new()
+        }
+    }
+}
+
+/// A directory served under a URL prefix, and optionally only for one host, from the `[[mounts]]` array of tables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountConf {
+    /// The URL prefix the directory is served under, such as `/app/`
+    pub prefix: String,
+    /// Only serve the mount for requests whose `Host` is this, ignoring the port
+    pub host: Option<String>,
+    /// The directory to serve
+    pub dist: PathBuf,
+    /// Served at the prefix itself and for fallbacks, defaults to the first of `paths.index_files` within `dist`
+    pub index: Option<PathBuf>,
+    /// History API fallback within the mount, patterns match the full path including the prefix
+    pub fallback: Option<FallbackConf>,
+    /// Headers set on every response from the mount, replacing those of the same name set by the rules
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>
+}
+
+impl MountConf {
+    /// The prefix without its leading and trailing slashes, empty for the root
+    #[inline]
+    #[must_use]
+    pub fn trimmed_prefix(&self) -> &str {
+        self.prefix.trim_matches('/')
+    }
+}
+
+/// Headers attached to the files whose path and MIME type match, from the `[[headers]]` array of tables. A rule without
+/// either pattern applies to every file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRuleConf {
+    /// Matched against the full request path, percent-decoded
+    pub path: Option<Glob>,
+    /// Matched against the MIME type without its parameters, such as `text/html` or `image/*`
+    pub mime: Option<Glob>,
+    /// Common sets of headers, applied before `set`
+    #[serde(default)]
+    pub presets: Vec<Preset>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>
+}
+
+/// Cross-origin resource sharing, enabled by the presence of the `[cors]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConf {
+    /// Origins responses are shared with, such as `http://localhost:*`, or `*` for any
+    pub origins: Vec<Glob>,
+    /// Methods preflight requests may ask for
+    pub methods: Vec<String>,
+    /// Request headers preflight requests may ask for, or `*` for any
+    pub headers: Vec<String>,
+    /// Response headers scripts may read besides the safelisted ones
+    pub expose: Vec<String>,
+    /// Whether requests may carry cookies and other credentials
+    pub credentials: bool,
+    /// Seconds the browser may cache the answer to a preflight request
+    pub max_age: Option<u64>
+}
+
+impl Default for CorsConf {
+    fn default() -> Self {
+        Self {
+            origins: vec![Glob::new("*").unwrap(/* infallible, the pattern is not empty */)],
+            methods: vec!["GET".to_owned(), "HEAD".to_owned()],
+            headers: vec!["*".to_owned()],
+            expose: Vec::new(),
+            credentials: false,
+            max_age: None
+        }
+    }
+}
+
+/// Live reloading of pages in the browser, enabled by the presence of the `[live_reload]` table. Requires the `reload`
+/// feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveReloadConf {
+    /// Where pages subscribe to changes, the client script is served at the same path with `.js` appended
+    pub path: String,
+    /// Add the client script to the index file, before `</body>`
+    pub inject: bool,
+    /// Paths of service workers, changes to which update their registration rather than reload the page
+    pub service_workers: Vec<Glob>
+}
+
+impl Default for LiveReloadConf {
+    fn default() -> Self {
+        Self {
+            path: "/__live-reload".to_owned(),
+            inject: false,
+            service_workers: ["/**/sw.js", "/**/service-worker.js"].into_iter()
+                .map(|pattern| Glob::new(pattern).unwrap(/* infallible, the patterns are not empty */))
+                .collect()
+        }
+    }
+}
+
+/// A single source of configuration, every field is optional so that sources can be layered.
+#[derive(Default, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    port: Option<u16>,
+    host: Option<IpAddr>,
+    index: Option<PathBuf>,
+    dist: Option<PathBuf>,
+    threads: Option<usize>,
+    http: Option<HttpConf>,
+    paths: Option<PathsConf>,
+    bad_cache: Option<BadCacheConf>,
+    fallback: Option<FallbackConf>,
+    mounts: Option<Vec<MountConf>>,
+    errors: Option<BTreeMap<u16, String>>,
+    headers: Option<Vec<HeaderRuleConf>>,
+    cors: Option<CorsConf>,
+    live_reload: Option<LiveReloadConf>,
+    tls: Option<TlsConf>
+}
+
+impl Layer {
+    /// Fill any fields `self` left unset with those of `lower`.
+    #[must_use]
+    fn or(self, lower: Self) -> Self {
+        Self {
+            port: self.port.or(lower.port),
+            host: self.host.or(lower.host),
+            index: self.index.or(lower.index),
+            dist: self.dist.or(lower.dist),
+            threads: self.threads.or(lower.threads),
+            http: self.http.or(lower.http),
+            paths: self.paths.or(lower.paths),
+            bad_cache: self.bad_cache.or(lower.bad_cache),
+            fallback: self.fallback.or(lower.fallback),
+            mounts: self.mounts.or(lower.mounts),
+            errors: self.errors.or(lower.errors),
+            headers: self.headers.or(lower.headers),
+            cors: self.cors.or(lower.cors),
+            live_reload: self.live_reload.or(lower.live_reload),
+            tls: self.tls.or(lower.tls)
+        }
+    }
+
+    fn from_env() -> io::Result<Self> {
+        Ok(Self {
+            port: parse_var("TEST_PORT")?,
+            host: parse_var("TEST_HOST")?,
+            index: env::var_os("TEST_SITE_PATH").map(PathBuf::from),
+            dist: env::var_os("TEST_DIST_PATH").map(PathBuf::from),
+            // `SERVER-THREADS` is what this was originally called, though most shells cannot export it.
+            threads: match parse_var("SERVER_THREADS")? {
+                Some(threads) => Some(threads),
+                None => parse_var("SERVER-THREADS")?
+            },
+            http: None,
+            paths: None,
+            bad_cache: None,
+            fallback: None,
+            mounts: None,
+            errors: None,
+            headers: None,
+            cors: None,
+            live_reload: None,
+            tls: None
+        })
+    }
+
+    /// Read a TOML config file, relative paths within it are resolved against the file's directory.
+    fn from_file(path: &Path) -> io::Result<Self> {
+        let raw = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Could not read config `{}`: {e}", path.display())))?;
+        let layer: Self = toml::from_str(&raw)
+            .map_err(|e| invalid!("Invalid config `{}`: {e}", path.display()))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        Ok(Self {
+            index: layer.index.map(|index| base.join(index)),
+            dist: layer.dist.map(|dist| base.join(dist)),
+            paths: layer.paths.map(|paths| PathsConf {
+                allow_symlinks: paths.allow_symlinks.into_iter().map(|dir| base.join(dir)).collect(),
+                ..paths
+            }),
+            mounts: layer.mounts.map(|mounts| mounts.into_iter().map(|mount| MountConf {
+                dist: base.join(mount.dist),
+                index: mount.index.map(|index| base.join(index)),
+                ..mount
+            }).collect()),
+            tls: layer.tls.map(|tls| TlsConf {
+                cert: tls.cert.map(|cert| base.join(cert)),
+                key: tls.key.map(|key| base.join(key)),
+                cert_dir: tls.cert_dir.map(|dir| base.join(dir)),
+                ..tls
+            }),
+            ..layer
+        })
+    }
+}
+
+/// The name of the header if it could not be sent, `None` if it is fine.
+fn invalid_header<'h>(name: &'h str, value: &str) -> Option<&'h str> {
+    let valid = !name.is_empty() && name.bytes().all(crate::parse::is_token) && !value.contains(['\r', '\n']);
+    (!valid).then_some(name)
+}
+
+fn parse_var<T>(name: &'static str) -> io::Result<Option<T>>
+    where T: FromStr, T::Err: fmt::Display
+{
+    match env::var(name) {
+        Ok(raw) => raw.parse()
+            .map(Some)
+            .map_err(|e| invalid!("Invalid value for environment variable `{name}` (`{raw}`): {e}")),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(invalid!("Environment variable `{name}` is not valid UTF-8"))
+    }
+}
+
+#[derive(Default, Debug)]
+#[must_use]
+struct Cli {
+    layer: Layer,
+    config: Option<PathBuf>,
+    tls: bool,
+    help: bool
+}
+
+impl Cli {
+    fn parse<I: IntoIterator<Item = OsString>>(args: I) -> io::Result<Self> {
+        fn value<T>(flag: &str, raw: Option<String>) -> io::Result<T>
+            where T: FromStr, T::Err: fmt::Display
+        {
+            let raw = raw.ok_or_else(|| invalid!("`{flag}` expects a value\n\n{USAGE}"))?;
+            raw.parse().map_err(|e| invalid!("Invalid value for `{flag}` (`{raw}`): {e}"))
+        }
+
+        let mut cli = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let arg = arg.into_string()
+                .map_err(|arg| invalid!("Argument `{}` is not valid UTF-8", arg.to_string_lossy()))?;
+
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_owned(), Some(value.to_owned())),
+                _ => (arg, None)
+            };
+
+            macro_rules! next_value {
+                () => {
+                    match inline {
+                        Some(__value) => Some(__value),
+                        None => args.next().map(|__value| __value.to_string_lossy().into_owned())
+                    }
+                }
+            }
+
+            match flag.as_str() {
+                "-p" | "--port" => cli.layer.port = Some(value(&flag, next_value!())?),
+                "--host" => cli.layer.host = Some(value(&flag, next_value!())?),
+                "-i" | "--index" => cli.layer.index = Some(value(&flag, next_value!())?),
+                "-d" | "--dist" => cli.layer.dist = Some(value(&flag, next_value!())?),
+                "-t" | "--threads" => cli.layer.threads = Some(value(&flag, next_value!())?),
+                "-c" | "--config" => cli.config = Some(value(&flag, next_value!())?),
+                "--tls" => cli.tls = true,
+                "-h" | "--help" => cli.help = true,
+                _ => return Err(invalid!("Unknown argument `{flag}`\n\n{USAGE}"))
+            }
+        }
+
+        Ok(cli)
+    }
+}
+
+#[derive(Debug)]
+#[must_use]
+pub struct ServerConf {
+    pub port: u16,
+    pub host: IpAddr,
+    pub index: &'static str,
+    pub dist: &'static str,
+    pub threads: usize,
+    pub http: HttpConf,
+    pub paths: PathsConf,
+    pub bad_cache: BadCacheConf,
+    pub fallback: Option<FallbackConf>,
+    pub mounts: Vec<MountConf>,
+    /// Pages served in place of the plain text body of errors by status, relative to the dist directory of each mount
+    pub errors: BTreeMap<u16, String>,
+    pub headers: Vec<HeaderRuleConf>,
+    pub cors: Option<CorsConf>,
+    pub live_reload: Option<LiveReloadConf>,
+    pub tls: Option<TlsConf>
+}
+
+impl ServerConf {
+    /// Load the configuration from the command line, environment and config file.
+    ///
+    /// Exits the process after printing usage if `--help` was requested.
+    #[instrument(name = "load-server-conf", err(Debug, level = Level::DEBUG))]
+    pub fn load() -> io::Result<Self> {
+        let cli = Cli::parse(env::args_os().skip(1))?;
+        if cli.help {
+            print!("{USAGE}");
+            std::process::exit(0);
+        }
+
+        let env = Layer::from_env()?;
+        let file = match cli.config.or_else(|| env::var_os("TEST_CONFIG").map(PathBuf::from)) {
+            Some(path) => Layer::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG).is_file() => Layer::from_file(Path::new(DEFAULT_CONFIG))?,
+            None => Layer::default()
+        };
+
+        let mut layer = cli.layer.or(env).or(file);
+        if cli.tls && layer.tls.is_none() {
+            layer.tls = Some(TlsConf::default());
+        }
+
+        let conf = Self::validate(layer)?;
+        conf.log();
+        Ok(conf)
+    }
+
+    fn validate(layer: Layer) -> io::Result<Self> {
+        fn leak_path(path: PathBuf, what: &str) -> io::Result<&'static str> {
+            path.into_os_string()
+                .into_string()
+                .map(|path| &*Box::leak(path.into_boxed_str()))
+                .map_err(|path| invalid!("The {what} path `{}` is not valid UTF-8", path.to_string_lossy()))
+        }
+
+        let index = layer.index.unwrap_or_else(|| PathBuf::from(DEFAULT_INDEX));
+        if !index.is_file() {
+            return Err(invalid!(
+                "The index file `{}` does not exist or is not a file \
+                (set with `--index`, `TEST_SITE_PATH` or `index` in the config file)",
+                index.display()
+            ));
+        }
+
+        let dist = match layer.dist {
+            Some(dist) if !dist.is_dir() => return Err(invalid!(
+                "The dist directory `{}` does not exist or is not a directory \
+                (set with `--dist`, `TEST_DIST_PATH` or `dist` in the config file)",
+                dist.display()
+            )),
+            Some(dist) => dist,
+            None => {
+                // the default is allowed to be missing, it just means everything but the index will 404.
+                if !Path::new(DEFAULT_DIST).is_dir() {
+                    warn!("The default dist directory `{DEFAULT_DIST}` does not exist");
+                }
+                PathBuf::from(DEFAULT_DIST)
+            }
+        };
+
+        let threads = layer.threads.unwrap_or_else(num_cpus::get);
+        if threads == 0 {
+            return Err(invalid!("The server requires at least one thread"));
+        }
+
+        let http = layer.http.unwrap_or_default();
+        if http.max_requests == 0 {
+            return Err(invalid!("`http.max_requests` must be at least 1"));
+        }
+        if http.max_concurrent_streams == 0 {
+            return Err(invalid!("`http.max_concurrent_streams` must be at least 1"));
+        }
+        if http.max_header_size < MIN_HEADER_SIZE {
+            return Err(invalid!("`http.max_header_size` must be at least {MIN_HEADER_SIZE} bytes"));
+        }
+
+        let mut paths = layer.paths.unwrap_or_default();
+        if let Some(name) = paths.index_files.iter()
+            .find(|name| matches!(name.as_str(), "" | "." | "..") || name.contains(['/', '\\']))
+        {
+            return Err(invalid!("`paths.index_files` must only contain file names, not `{name}`"));
+        }
+        if paths.confine {
+            // compared against canonical paths, so they must be canonical themselves
+            for dir in &mut paths.allow_symlinks {
+                *dir = fs::canonicalize(&*dir).map_err(|e| invalid!(
+                    "The symlink target `{}` in `paths.allow_symlinks` could not be resolved: {e}", dir.display()
+                ))?;
+            }
+        } else if !paths.allow_symlinks.is_empty() {
+            warn!("`paths.allow_symlinks` has no effect unless `paths.confine` is set");
+        }
+
+        if layer.bad_cache.is_some() && cfg!(not(feature = "bad-cache")) {
+            return Err(invalid!(
+                "`bad_cache` was configured, but the server was built without the `bad-cache` feature"
+            ));
+        }
+
+        let fallbacks = layer.fallback.iter()
+            .chain(layer.mounts.iter().flatten().filter_map(|mount| mount.fallback.as_ref()));
+        for fallback in fallbacks {
+            let relative = fallback.include.iter()
+                .chain(&fallback.exclude)
+                .find(|glob| !glob.as_str().starts_with('/'));
+            if let Some(glob) = relative {
+                return Err(invalid!("`fallback` patterns must start with `/`, unlike `{}`", glob.as_str()));
+            }
+        }
+
+        let mounts = layer.mounts.unwrap_or_default();
+        for (i, mount) in mounts.iter().enumerate() {
+            let prefix = &mount.prefix;
+            if !prefix.starts_with('/') || prefix.contains("//") || prefix.contains(['?', '#', ' ']) {
+                return Err(invalid!("The mount prefix `{prefix}` must be a path starting with `/`"));
+            }
+            if mount.trimmed_prefix().is_empty() && mount.host.is_none() {
+                return Err(invalid!("Mounts at `/` need a `host`, the root is served from `dist` and `index`"));
+            }
+            let duplicate = mounts[..i].iter()
+                .any(|other| other.trimmed_prefix() == mount.trimmed_prefix() && other.host == mount.host);
+            if duplicate {
+                return Err(invalid!("The mount prefix `{prefix}` is used more than once for the same host"));
+            }
+            if !mount.dist.is_dir() {
+                return Err(invalid!(
+                    "The dist directory `{}` of the mount `{prefix}` does not exist or is not a directory",
+                    mount.dist.display()
+                ));
+            }
+            if let Some(index) = mount.index.as_ref().filter(|index| !index.is_file()) {
+                return Err(invalid!(
+                    "The index file `{}` of the mount `{prefix}` does not exist or is not a file", index.display()
+                ));
+            }
+            if let Some(name) = mount.headers.iter().find_map(|(name, value)| invalid_header(name, value)) {
+                return Err(invalid!("The header `{name}` of the mount `{prefix}` is not a valid header"));
+            }
+        }
+
+        let errors = layer.errors.unwrap_or_default();
+        for (code, page) in &errors {
+            if !crate::response::Status::from_code(*code).is_some_and(|status| status.is_error()) {
+                return Err(invalid!("`errors` only takes the error statuses we respond with, not `{code}`"));
+            }
+            // served by routing them through the dist directory like any other request
+            let routable = page.split('/').all(|segment| !matches!(segment, "" | "." | ".."))
+                && !page.contains(['%', '?', '#', '\\']);
+            if !routable {
+                return Err(invalid!("The error page `{page}` must be a relative path within the dist directory"));
+            }
+        }
+
+        let headers = layer.headers.unwrap_or_default();
+        for rule in &headers {
+            if let Some(glob) = rule.path.as_ref().filter(|glob| !glob.as_str().starts_with('/')) {
+                return Err(invalid!("`headers` path patterns must start with `/`, unlike `{}`", glob.as_str()));
+            }
+            if rule.presets.is_empty() && rule.set.is_empty() {
+                return Err(invalid!("`headers` rules must have `presets` or headers to `set`"));
+            }
+            if let Some(name) = rule.set.iter().find_map(|(name, value)| invalid_header(name, value)) {
+                return Err(invalid!("The header `{name}` in `headers` is not a valid header"));
+            }
+        }
+
+        if let Some(cors) = &layer.cors {
+            if cors.origins.is_empty() {
+                return Err(invalid!("`cors.origins` must name at least one origin, or `*` for any"));
+            }
+            let token = |name: &String| !name.is_empty() && name.bytes().all(crate::parse::is_token);
+            if let Some(method) = cors.methods.iter().find(|method| !token(method)) {
+                return Err(invalid!("`{method}` in `cors.methods` is not a valid method"));
+            }
+            let names = cors.headers.iter().filter(|name| *name != "*").chain(&cors.expose);
+            if let Some(name) = names.into_iter().find(|name| !token(name)) {
+                return Err(invalid!("`{name}` in `cors.headers` or `cors.expose` is not a valid header name"));
+            }
+            if cors.credentials {
+                // any site could otherwise read whatever the user's cookies give access to, which is why browsers
+                // refuse `*` along with credentials
+                let explicit = |glob: &&Glob| glob.as_str().split_once("://").is_some_and(|(scheme, authority)| {
+                    let host = authority.split(':').next().unwrap_or_default();
+                    !scheme.contains('*') && host.contains(|c: char| !matches!(c, '*' | '.'))
+                });
+                if let Some(glob) = cors.origins.iter().find(|glob| !explicit(glob)) {
+                    return Err(invalid!(
+                        "`cors.credentials` requires explicit origins such as `http://localhost:*`, not `{}`",
+                        glob.as_str()
+                    ));
+                }
+            }
+        }
+
+        if let Some(live) = &layer.live_reload {
+            if cfg!(not(feature = "reload")) {
+                return Err(invalid!(
+                    "Live reloading was configured, but the server was built without the `reload` feature"
+                ));
+            }
+            // the path is written into the client script and the injected tag as is, so it is kept to unreserved
+            // characters which need no escaping in either
+            let path = &live.path;
+            let unreserved = |segment: &str| !matches!(segment, "" | "." | "..") && segment.bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~'));
+            if !path.strip_prefix('/').is_some_and(|path| path.split('/').all(unreserved)) {
+                return Err(invalid!(
+                    "`live_reload.path` must be a path starting with `/` of letters, digits, `-`, `.`, `_` and `~`, \
+                    not `{path}`"
+                ));
+            }
+            if let Some(glob) = live.service_workers.iter().find(|glob| !glob.as_str().starts_with('/')) {
+                return Err(invalid!(
+                    "`live_reload.service_workers` patterns must start with `/`, unlike `{}`", glob.as_str()
+                ));
+            }
+        }
+
+        let port = layer.port.unwrap_or(DEFAULT_PORT);
+        if let Some(tls) = &layer.tls {
+            if cfg!(not(feature = "tls")) {
+                return Err(invalid!("TLS was configured, but the server was built without the `tls` feature"));
+            }
+            match (&tls.cert, &tls.key) {
+                (Some(_), None) | (None, Some(_)) => return Err(invalid!(
+                    "`tls.cert` and `tls.key` must be set together, or both left unset to generate a certificate"
+                )),
+                (Some(cert), Some(key)) => for (path, what) in [(cert, "certificate"), (key, "private key")] {
+                    if !path.is_file() {
+                        return Err(invalid!("The TLS {what} `{}` does not exist or is not a file", path.display()));
+                    }
+                },
+                (None, None) => ()
+            }
+            if tls.redirect_port == Some(port) {
+                return Err(invalid!("`tls.redirect_port` must differ from the port HTTPS is served on"));
+            }
+        }
+
+        Ok(Self {
+            port,
+            host: layer.host.unwrap_or(DEFAULT_HOST),
+            index: leak_path(index, "index")?,
+            dist: leak_path(dist, "dist")?,
+            threads,
+            http,
+            paths,
+            bad_cache: layer.bad_cache.unwrap_or_default(),
+            fallback: layer.fallback,
+            mounts,
+            errors,
+            headers,
+            cors: layer.cors,
+            live_reload: layer.live_reload,
+            tls: layer.tls
+        })
+    }
+
+    fn log(&self) {
+        macro_rules! cfg_has {
+            ($meta:meta) => {{
+                #[cfg($meta)] {
+                    true
+                }
+                #[cfg(not($meta))] {
+                    false
+                }
+            }}
+        }
+
+        const RELOADS: bool = cfg_has!(feature = "reload");
+        const BAD_CACHE: bool = cfg_has!(feature = "bad-cache");
+
+        let Self {
+            port, host, index, dist, threads, http, paths, bad_cache, fallback, mounts, errors, headers, cors,
+            live_reload, tls
+        } = self;
+        let addr = std::net::SocketAddr::new(*host, *port);
+        let tls = match tls {
+            Some(TlsConf { cert: Some(cert), .. }) => format!("{}", cert.display()),
+            Some(tls) => format!("generated in {}", tls.cert_dir().display()),
+            None => "off".to_owned()
+        };
+
+        info!(
+            "Server Configuration:\
+            \n\t INDEX: {index},\
+            \n\t DIST: {dist},\
+            \n\t ADDRESS: {addr},\
+            \n\t TLS: {tls},\
+            \n\t THREADS: {threads},\
+            \n\t KEEP-ALIVE: {}s, {} requests,\
+            \n\t MAX HEADER SIZE: {} bytes, READ TIMEOUT: {}s,\
+            \n\t PATH CHARSET: {}, DENIED: {:?}, CONFINED: {}, LISTINGS: {},\
+            \n\t SPA FALLBACK: {},\
+            \n\t MOUNTS: {},\
+            \n\t ERROR PAGES: {},\
+            \n\t HEADER RULES: {},\
+            \n\t CORS: {},\
+            \n\t HOT RELOADS: {RELOADS}, LIVE RELOAD: {},\
+            \n\t 404 CACHING: {BAD_CACHE}, {} paths for {}s",
+            http.keep_alive_timeout, http.max_requests, http.max_header_size, http.read_timeout,
+            paths.charset.as_str(), paths.deny, paths.confine, paths.listing,
+            fallback.is_some(),
+            mounts.iter()
+                .map(|mount| format!(
+                    "{}{} => {}", mount.host.as_deref().unwrap_or_default(), mount.prefix, mount.dist.display()
+                ))
+                .collect::<Vec<_>>()
+                .join(", "),
+            errors.iter().map(|(code, page)| format!("{code} => {page}")).collect::<Vec<_>>().join(", "),
+            headers.len(),
+            cors.as_ref().map_or_else(
+                || "off".to_owned(),
+                |cors| cors.origins.iter().map(Glob::as_str).collect::<Vec<_>>().join(", ")
+            ),
+            live_reload.as_ref().map_or_else(
+                || "off".to_owned(),
+                |live| format!("{}{}", live.path, if live.inject { ", injected" } else { "" })
+            ),
+            bad_cache.capacity, bad_cache.ttl
+        );
+    }
+}
+
+#[cfg(test)]
+impl ServerConf {
+    /// The defaults, serving `index` and leaving the dist directory empty
+    pub fn for_tests(index: &str) -> Self {
+        Self {
+            port: 0,
+            host: DEFAULT_HOST,
+            index: Box::leak(index.to_owned().into_boxed_str()),
+            dist: DEFAULT_DIST,
+            threads: 1,
+            http: HttpConf::default(),
+            paths: PathsConf::default(),
+            bad_cache: BadCacheConf::default(),
+            fallback: None,
+            mounts: Vec::new(),
+            errors: BTreeMap::new(),
+            headers: Vec::new(),
+            cors: None,
+            live_reload: None,
+            tls: None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(raw: &[&str]) -> Vec<OsString> {
+        raw.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn cli_flags() {
+        let cli = Cli::parse(args(&["-p", "8080", "--dist=out", "--threads", "2", "-c", "conf.toml", "--tls"])).unwrap();
+        assert_eq!(cli.layer.port, Some(8080));
+        assert!(cli.tls);
+        assert_eq!(cli.layer.dist.as_deref(), Some(Path::new("out")));
+        assert_eq!(cli.layer.threads, Some(2));
+        assert_eq!(cli.config.as_deref(), Some(Path::new("conf.toml")));
+        assert!(cli.layer.index.is_none());
+
+        assert!(Cli::parse(args(&["--port", "not-a-port"])).is_err());
+        assert!(Cli::parse(args(&["--port"])).is_err());
+        assert!(Cli::parse(args(&["--host", "localhost"])).is_err());
+        assert_eq!(Cli::parse(args(&["--host=0.0.0.0"])).unwrap().layer.host, Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+        assert!(Cli::parse(args(&["--unknown"])).is_err());
+    }
+
+    #[test]
+    fn layer_precedence() {
+        let cli = Layer { port: Some(1), ..Layer::default() };
+        let env = Layer { port: Some(2), threads: Some(4), ..Layer::default() };
+        let file: Layer = toml::from_str("port = 3\nthreads = 8\ndist = \"out\"").unwrap();
+
+        let merged = cli.or(env).or(file);
+        assert_eq!(merged.port, Some(1));
+        assert_eq!(merged.threads, Some(4));
+        assert_eq!(merged.dist.as_deref(), Some(Path::new("out")));
+
+        assert!(toml::from_str::<Layer>("prot = 3").is_err());
+
+        let file: Layer = toml::from_str("[paths]\ncharset = \"ascii\"\ndeny = \"<>\"").unwrap();
+        let paths = file.paths.unwrap();
+        assert_eq!((paths.charset, paths.deny.as_str()), (Charset::Ascii, "<>"));
+        assert!(toml::from_str::<Layer>("[paths]\ncharset = \"latin1\"").is_err());
+
+        let file: Layer = toml::from_str("[fallback]\nexclude = [\"/api/**\"]").unwrap();
+        let fallback = file.fallback.unwrap();
+        assert!(fallback.include[0].matches("/users/42"));
+        assert!(fallback.exclude[0].matches("/api/users"));
+
+        let file: Layer = toml::from_str(
+            "[[mounts]]\nprefix = \"/app/\"\ndist = \"app\"\n[mounts.headers]\nService-Worker-Allowed = \"/app/\"\n\n\
+            [[mounts]]\nprefix = \"/\"\nhost = \"admin.test\"\ndist = \"admin\"\n[mounts.fallback]"
+        ).unwrap();
+        let mounts = file.mounts.unwrap();
+        assert_eq!(mounts[0].trimmed_prefix(), "app");
+        assert_eq!(mounts[0].headers["Service-Worker-Allowed"], "/app/");
+        assert_eq!((mounts[1].trimmed_prefix(), mounts[1].host.as_deref()), ("", Some("admin.test")));
+        assert!(mounts[1].fallback.is_some());
+
+        let file: Layer = toml::from_str("[errors]\n404 = \"404.html\"\n500 = \"errors/500.html\"").unwrap();
+        let errors = file.errors.unwrap();
+        assert_eq!((errors[&404].as_str(), errors[&500].as_str()), ("404.html", "errors/500.html"));
+        assert!(toml::from_str::<Layer>("[errors]\nmissing = \"404.html\"").is_err());
+    }
+
+    #[test]
+    fn credentials_need_explicit_origins() {
+        let validate = |cors: &str| ServerConf::validate(toml::from_str(&format!("[cors]\n{cors}")).unwrap());
+
+        for origins in ["[]", "[\"*\"]", "[\"http://localhost:*\", \"*\"]", "[\"*://app.test\"]", "[\"https://*\"]"] {
+            let err = validate(&format!("credentials = true\norigins = {origins}")).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{origins}");
+        }
+        assert!(validate("credentials = true").is_err());
+        assert!(validate("credentials = true\norigins = [\"http://localhost:*\", \"https://*.app.test\"]").is_ok());
+        assert!(validate("origins = [\"*\"]").is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "reload")]
+    fn live_reload_paths_are_unreserved() {
+        let validate = |path: &str| ServerConf::validate(
+            toml::from_str(&format!("[live_reload]\npath = {}", toml::Value::from(path))).unwrap()
+        );
+
+        for path in ["", "live", "/", "/live/", "//live", "/a/../live", "/./live", "/live\"", "/live'", "/<live>",
+            "/live\\", "/live?", "/live%20", "/live reload"] {
+            let err = validate(path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{path}");
+        }
+        assert!(validate("/__live-reload").is_ok());
+        assert!(validate("/dev/live.reload_~1").is_ok());
+    }
+}
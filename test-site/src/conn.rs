@@ -0,0 +1,353 @@
+//! HTTP/1 connections, with keep-alive and pipelining, handing over to HTTP/2 on receiving its preface
+use crate::conf::HttpConf;
+use crate::parse::{Request, ParseError, Version, Method};
+use crate::response::{Response, Status};
+#[cfg(feature = "reload")]
+use crate::response::Body;
+use crate::Handler;
+#[cfg(feature = "http2")]
+use crate::http2;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::{instrument, trace, debug, Level};
+
+/// The initial size of a connection's buffer, grown as required up to `max_header_size`.
+const INITIAL_BUF: usize = 1024;
+
+/// How long to keep discarding a rejected request before closing the connection.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Accumulates the bytes of a connection until they form a complete request head.
+#[must_use]
+struct Buf {
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    max: usize
+}
+
+impl Buf {
+    #[inline]
+    pub fn new(max: usize) -> Self {
+        Self { buf: vec![0; core::cmp::min(INITIAL_BUF, max)], start: 0, end: 0, max }
+    }
+
+    /// Read more bytes from the stream after any unconsumed (pipelined) bytes, returning how many were read.
+    #[inline]
+    pub async fn read<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> io::Result<usize> {
+        if self.start != 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        if self.end == self.buf.len() {
+            let grown = core::cmp::min(self.buf.len() * 2, self.max);
+            self.buf.resize(grown, 0);
+        }
+        let read = stream.read(&mut self.buf[self.end..]).await?;
+        self.end += read;
+        Ok(read)
+    }
+
+    #[inline]
+    pub fn get(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    /// Discard the first `len` bytes, these belonged to a request which has been served.
+    #[inline]
+    pub fn consume(&mut self, len: usize) {
+        self.start = core::cmp::min(self.start + len, self.end);
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn is_full(&self) -> bool {
+        self.end - self.start >= self.max
+    }
+}
+
+enum ReadError {
+    /// The connection was closed before a complete request was received
+    Closed,
+    Io(io::Error),
+    Respond(Status)
+}
+
+/// Read until `buf` holds a complete request head, or the client exceeds our limits.
+async fn read_head<S>(stream: &mut S, buf: &mut Buf, conf: &HttpConf) -> Result<(), ReadError>
+    where S: AsyncRead + Unpin
+{
+    // the deadline is for the entire head, otherwise a client could hold the connection indefinitely by sending a
+    // byte at a time.
+    let deadline = Instant::now() + conf.read_timeout();
+
+    while matches!(Request::parse(buf.get()), Err(ParseError::Incomplete)) {
+        if buf.is_full() {
+            // if we have not even seen the end of the request line, it is the target that is too long
+            return Err(ReadError::Respond(if buf.get().contains(&b'\n') {
+                Status::HEADER_TOO_LARGE
+            } else {
+                Status::URI_TOO_LONG
+            }));
+        }
+
+        // a head can only have been completed by a read containing a line feed, so there's no need to re-parse until
+        // we see one.
+        loop {
+            let before = buf.get().len();
+            match timeout_at(deadline, buf.read(stream)).await {
+                Ok(Ok(0)) => return Err(ReadError::Closed),
+                Ok(Ok(_)) if buf.get()[before..].contains(&b'\n') => break,
+                Ok(Ok(_)) if buf.is_full() => break,
+                Ok(Ok(_)) => (),
+                Ok(Err(err)) => return Err(ReadError::Io(err)),
+                Err(_) => return Err(ReadError::Respond(Status::REQUEST_TIMEOUT))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Respond with `status` and close the connection.
+async fn reject<S>(stream: &mut S, status: Status) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    debug!("Rejecting the request: {}", status.reason());
+    Response::status(status).write_h1(stream, false, false).await?;
+    linger(stream).await
+}
+
+/// Close a connection which the client may still be sending to.
+///
+/// Closing a socket with unread data makes the kernel reset the connection, which can discard our response before the
+/// client reads it. So we shut down our side and discard what the client sends for a moment before closing.
+async fn linger<S>(stream: &mut S) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    stream.shutdown().await?;
+
+    let mut discard = [0; 1024];
+    let _ = timeout(LINGER, async {
+        while stream.read(&mut discard).await.is_ok_and(|read| read != 0) {}
+    }).await;
+    Ok(())
+}
+
+/// Write server-sent events after their head until the client goes away, which is what ends the body.
+#[cfg(feature = "reload")]
+async fn stream_events<S>(stream: &mut S, mut events: crate::live::Receiver) -> io::Result<()>
+    where S: AsyncWrite + Unpin
+{
+    stream.write_all(crate::live::PREAMBLE).await?;
+    stream.flush().await?;
+    while let Some(event) = crate::live::next(&mut events).await {
+        stream.write_all(&event).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+/// Whether the client permits the connection to be reused after this request.
+#[inline]
+#[must_use]
+fn wants_keep_alive(req: &Request) -> bool {
+    let has_token = |token: &str| req.headers.get_all("connection")
+        .flat_map(|value| value.split(|byte| *byte == b','))
+        .any(|option| option.trim_ascii().eq_ignore_ascii_case(token.as_bytes()));
+
+    match req.version {
+        Version::Http11 | Version::Http2 => !has_token("close"),
+        Version::Http10 => has_token("keep-alive")
+    }
+}
+
+/// Whether the request carries a body. We have no use for request bodies, so rather than reading past them to find the
+/// next pipelined request we simply close the connection after responding.
+///
+/// Bodies larger than `max_body_size` are rejected outright, as is a `Content-Length` which is not a number, since we
+/// could not tell where the body ends.
+fn has_body(req: &Request, conf: &HttpConf) -> Result<bool, Status> {
+    if req.headers.get("transfer-encoding").is_some() {
+        return Ok(true);
+    }
+    let Some(len) = req.headers.get("content-length") else { return Ok(false) };
+
+    let len = core::str::from_utf8(len.trim_ascii()).ok()
+        .filter(|len| !len.is_empty() && len.bytes().all(|byte| byte.is_ascii_digit()))
+        .map(|len| len.parse::<u64>().unwrap_or(u64::MAX))
+        .ok_or(Status::BAD_REQUEST)?;
+    if len > conf.max_body_size {
+        return Err(Status::PAYLOAD_TOO_LARGE);
+    }
+    Ok(len != 0)
+}
+
+#[instrument(
+    name = "connection",
+    skip_all,
+    err(Debug, level = Level::DEBUG),
+    level = Level::DEBUG
+)]
+pub async fn serve<S>(mut stream: S, handler: Handler, conf: &'static HttpConf) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut buf = Buf::new(conf.max_header_size);
+    let mut served = 0usize;
+
+    loop {
+        // only the time spent waiting for the next request counts towards the idle timeout
+        if buf.is_empty() {
+            match timeout(conf.keep_alive_timeout(), buf.read(&mut stream)).await {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(_)) => (),
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    debug!("Connection was idle for {}s, closing", conf.keep_alive_timeout);
+                    return Ok(());
+                }
+            }
+        }
+
+        match read_head(&mut stream, &mut buf, conf).await {
+            Ok(()) => (),
+            Err(ReadError::Closed) => return Ok(()),
+            Err(ReadError::Io(err)) => return Err(err),
+            Err(ReadError::Respond(status)) => return reject(&mut stream, status).await
+        }
+
+        let req = match Request::parse(buf.get()) {
+            Ok(req) => req,
+            #[cfg(feature = "http2")]
+            Err(ParseError::UnsupportedVersion(_)) if served == 0 && buf.get().starts_with(&http2::PREFACE[..16]) => {
+                debug!("Received the HTTP/2 connection preface, switching protocols (h2c)");
+                return http2::serve(http2::Rewind::new(buf.get().to_vec(), stream), handler, conf).await;
+            },
+            Err(err) => {
+                debug!("Failed to parse the request: {err}");
+                let status = match err {
+                    ParseError::UnsupportedVersion(_) => Status::VERSION_NOT_SUPPORTED,
+                    _ => Status::BAD_REQUEST
+                };
+                return reject(&mut stream, status).await;
+            }
+        };
+
+        let body = match has_body(&req, conf) {
+            Ok(body) => body,
+            Err(status) => return reject(&mut stream, status).await
+        };
+
+        served += 1;
+        let keep_alive = served < conf.max_requests && wants_keep_alive(&req) && !body;
+        trace!("Serving request {served} on this connection, keep-alive: {keep_alive}");
+
+        let (consumed, head_only) = (req.len, req.method == Method::Head);
+        let res = handler.respond(&req).await;
+        // streamed bodies are only framed by closing the connection
+        let keep_alive = keep_alive && !res.body.is_streamed();
+        // subscribed before the head is written, so that no event is missed in between
+        #[cfg(feature = "reload")]
+        let events = match &res.body {
+            Body::Events(events) if !head_only => Some(events.subscribe()),
+            _ => None
+        };
+        res.write_h1(&mut stream, keep_alive, head_only).await?;
+        #[cfg(feature = "reload")]
+        if let Some(events) = events {
+            return stream_events(&mut stream, events).await;
+        }
+
+        if body {
+            return linger(&mut stream).await;
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+        buf.consume(consumed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &'static [u8], max_header_size: usize) -> (Result<(), ReadError>, usize) {
+        let conf = HttpConf { max_header_size, read_timeout: 1, ..HttpConf::default() };
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                let (mut client, mut server) = tokio::io::duplex(64);
+                // hold the client open, so that running out of input is a timeout rather than a close
+                let writer = tokio::spawn(async move {
+                    let _ = client.write_all(raw).await;
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                });
+                let mut buf = Buf::new(max_header_size);
+                let res = read_head(&mut server, &mut buf, &conf).await;
+                writer.abort();
+                (res, buf.get().len())
+            })
+    }
+
+    macro_rules! status {
+        ($res:expr) => {
+            match $res {
+                Err(ReadError::Respond(status)) => Some(status),
+                _ => None
+            }
+        };
+    }
+
+    #[test]
+    fn accumulates_split_heads() {
+        let raw = b"GET /a/b.js HTTP/1.1\r\nHost: localhost\r\nCookie: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n";
+        let (res, len) = read(raw, 1024);
+        assert!(res.is_ok());
+        assert_eq!(len, raw.len());
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(status!(read(&[b'a'; 2048], 1024).0), Some(Status::URI_TOO_LONG));
+
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        raw.extend_from_slice(&[b'a'; 2048]);
+        assert_eq!(status!(read(raw.leak(), 1024).0), Some(Status::HEADER_TOO_LARGE));
+
+        assert_eq!(status!(read(b"GET / HTTP/1.1\r\nHost: localhost\r\n", 1024).0), Some(Status::REQUEST_TIMEOUT));
+    }
+
+    #[test]
+    fn bodies() {
+        let conf = HttpConf { max_body_size: 10, ..HttpConf::default() };
+        let body = |raw: &'static [u8]| has_body(&Request::parse(raw).unwrap(), &conf);
+
+        assert_eq!(body(b"GET / HTTP/1.1\r\n\r\n"), Ok(false));
+        assert_eq!(body(b"GET / HTTP/1.1\r\nContent-Length: 0\r\n\r\n"), Ok(false));
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n"), Ok(true));
+        assert_eq!(body(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"), Ok(true));
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n"), Err(Status::PAYLOAD_TOO_LARGE));
+        assert_eq!(
+            body(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n"),
+            Err(Status::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), Err(Status::BAD_REQUEST));
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 1, 1\r\n\r\n"), Err(Status::BAD_REQUEST));
+    }
+}
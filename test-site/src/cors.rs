@@ -0,0 +1,163 @@
+//! Cross-origin resource sharing, see the fetch standard section 3.2
+//!
+//! Every response to an allowed origin is marked as shareable with it, and preflight requests are answered directly
+//! rather than routed.
+use crate::conf::CorsConf;
+use crate::parse::{Method, Request};
+use crate::response::{Body, Response, Status};
+
+pub struct Cors {
+    conf: &'static CorsConf,
+    /// `Access-Control-Allow-Methods`
+    methods: String,
+    /// `Access-Control-Expose-Headers`, `None` if there are none
+    expose: Option<String>
+}
+
+impl Cors {
+    #[must_use]
+    pub fn new(conf: &'static CorsConf) -> Self {
+        Self {
+            conf,
+            methods: conf.methods.join(", "),
+            expose: (!conf.expose.is_empty()).then(|| conf.expose.join(", "))
+        }
+    }
+
+    #[inline]
+    fn any_origin(&self) -> bool {
+        self.conf.origins.iter().any(|glob| glob.as_str() == "*")
+    }
+
+    /// Whether every origin is answered alike, with `*`
+    #[inline]
+    fn wildcard(&self) -> bool {
+        self.any_origin() && !self.conf.credentials
+    }
+
+    /// The `Origin` of `req` if we share responses with it.
+    fn allowed_origin<'r>(&self, req: &Request<'r>) -> Option<&'r str> {
+        let origin = core::str::from_utf8(req.headers.get("origin")?.trim_ascii()).ok()?;
+        (self.any_origin() || self.conf.origins.iter().any(|glob| glob.matches(origin))).then_some(origin)
+    }
+
+    /// Mark `res` as shareable with the origin, credentials require it to be named rather than matched by `*`.
+    fn allow_origin(&self, origin: &str, res: Response) -> Response {
+        if self.wildcard() {
+            return res.header("Access-Control-Allow-Origin", "*");
+        }
+        let res = res.header("Access-Control-Allow-Origin", origin.to_owned());
+        if self.conf.credentials {
+            res.header("Access-Control-Allow-Credentials", "true")
+        } else {
+            res
+        }
+    }
+
+    /// Answer `req` if it is a preflight request, one the browser sends before a request which is not "simple" to ask
+    /// whether it may be sent at all. If the request is not allowed we answer without permission, which the browser
+    /// reports as a network error.
+    pub fn preflight(&self, req: &Request) -> Option<Response> {
+        if req.method != Method::Options {
+            return None;
+        }
+        let method = req.headers.get("access-control-request-method")?.trim_ascii();
+        let res = Response::new(Status::NO_CONTENT, Body::Empty)
+            .header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+
+        let Some(origin) = self.allowed_origin(req) else { return Some(res) };
+        if !self.conf.methods.iter().any(|allowed| allowed.as_bytes() == method) {
+            return Some(res);
+        }
+        let requested = req.headers.get_all("access-control-request-headers")
+            .flat_map(|value| value.split(|byte| *byte == b','))
+            .map(<[u8]>::trim_ascii)
+            .filter(|name| !name.is_empty())
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>();
+        let allowed = |name: &str| self.conf.headers.iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(name));
+        if !requested.iter().all(|name| allowed(name)) {
+            return Some(res);
+        }
+
+        let res = self.allow_origin(origin, res).header("Access-Control-Allow-Methods", self.methods.clone());
+        // `*` is not honoured with credentials, so the requested headers are named instead
+        let res = if requested.is_empty() {
+            res
+        } else {
+            res.header("Access-Control-Allow-Headers", requested.join(", "))
+        };
+        Some(match self.conf.max_age {
+            Some(max_age) => res.header("Access-Control-Max-Age", max_age.to_string()),
+            None => res
+        })
+    }
+
+    /// Share `res` with the origin of `req`, if it is allowed.
+    pub fn apply(&self, req: &Request, res: Response) -> Response {
+        // unless every origin is answered alike, caches must keep the response apart from those to other origins
+        let res = if self.wildcard() { res } else { res.header("Vary", "Origin") };
+        let Some(origin) = self.allowed_origin(req) else { return res };
+        let res = self.allow_origin(origin, res);
+        match &self.expose {
+            Some(expose) => res.header("Access-Control-Expose-Headers", expose.clone()),
+            None => res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn header(res: &Response, name: &str) -> Option<String> {
+        res.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value.to_string())
+    }
+
+    #[test]
+    fn preflights() {
+        let conf: &'static CorsConf = Box::leak(Box::new(toml::from_str(
+            "origins = [\"http://localhost:*\"]\nheaders = [\"X-Fixture\"]\ncredentials = true\nmax_age = 60"
+        ).unwrap()));
+        let cors = Cors::new(conf);
+        let parse = |raw: &'static str| Request::parse(raw.as_bytes()).unwrap();
+
+        let res = cors.preflight(&parse(
+            "OPTIONS /sw.js HTTP/1.1\r\nOrigin: http://localhost:3000\r\nAccess-Control-Request-Method: GET\r\n\
+            Access-Control-Request-Headers: x-fixture\r\n\r\n"
+        )).unwrap();
+        assert_eq!(res.status, Status::NO_CONTENT);
+        assert_eq!(header(&res, "access-control-allow-origin").as_deref(), Some("http://localhost:3000"));
+        assert_eq!(header(&res, "access-control-allow-credentials").as_deref(), Some("true"));
+        assert_eq!(header(&res, "access-control-allow-methods").as_deref(), Some("GET, HEAD"));
+        assert_eq!(header(&res, "access-control-allow-headers").as_deref(), Some("x-fixture"));
+        assert_eq!(header(&res, "access-control-max-age").as_deref(), Some("60"));
+
+        // not allowed, so without permission
+        for raw in [
+            "OPTIONS / HTTP/1.1\r\nOrigin: http://evil.test\r\nAccess-Control-Request-Method: GET\r\n\r\n",
+            "OPTIONS / HTTP/1.1\r\nOrigin: http://localhost:1\r\nAccess-Control-Request-Method: PUT\r\n\r\n",
+            "OPTIONS / HTTP/1.1\r\nOrigin: http://localhost:1\r\nAccess-Control-Request-Method: GET\r\n\
+            Access-Control-Request-Headers: authorization\r\n\r\n"
+        ] {
+            assert_eq!(header(&cors.preflight(&parse(raw)).unwrap(), "access-control-allow-origin"), None);
+        }
+        // plain `OPTIONS` requests are routed as usual
+        assert!(cors.preflight(&parse("OPTIONS / HTTP/1.1\r\nOrigin: http://localhost:1\r\n\r\n")).is_none());
+
+        let res = cors.apply(
+            &parse("GET / HTTP/1.1\r\nOrigin: http://localhost:1\r\n\r\n"),
+            Response::new(Status::OK, Body::Empty)
+        );
+        assert!(res.headers.contains(&("Vary", Cow::Borrowed("Origin"))));
+
+        let any: &'static CorsConf = Box::leak(Box::default());
+        let res = Cors::new(any).apply(
+            &parse("GET / HTTP/1.1\r\nOrigin: https://example.test\r\n\r\n"),
+            Response::new(Status::OK, Body::Empty)
+        );
+        assert_eq!(header(&res, "access-control-allow-origin").as_deref(), Some("*"));
+    }
+}
@@ -0,0 +1,228 @@
+//! Content codings and `Accept-Encoding` negotiation, see RFC 9110 section 12.5.3
+use crate::parse::Headers;
+#[cfg(feature = "compression")]
+use std::io::{self, Write};
+
+/// Files smaller than this are not worth compressing on the fly, the framing overhead eats most of the savings.
+#[cfg(feature = "compression")]
+const MIN_COMPRESS: usize = 256;
+
+/// The content codings we can serve, in our order of preference.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity
+}
+
+impl Encoding {
+    const ALL: [Self; 3] = [Self::Brotli, Self::Gzip, Self::Identity];
+
+    #[inline]
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Identity => "identity"
+        }
+    }
+
+    /// The extension of precompressed siblings, `bundle.js.br` for `bundle.js`
+    #[inline]
+    #[must_use]
+    pub const fn extension(&self) -> Option<&'static str> {
+        match self {
+            Self::Brotli => Some("br"),
+            Self::Gzip => Some("gz"),
+            Self::Identity => None
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    fn matches(&self, coding: &[u8]) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str().as_bytes())
+            // `x-gzip` is an alias of `gzip`
+            || (*self == Self::Gzip && coding.eq_ignore_ascii_case(b"x-gzip"))
+    }
+}
+
+/// Parse a qvalue as thousandths, so that preferences compare exactly.
+#[must_use]
+pub fn parse_qvalue(raw: &[u8]) -> Option<u16> {
+    let (int, frac) = match raw.iter().position(|byte| *byte == b'.') {
+        Some(dot) => (&raw[..dot], &raw[dot + 1..]),
+        None => (raw, &[][..])
+    };
+    if frac.len() > 3 || !frac.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let thousandths = frac.iter()
+        .chain(core::iter::repeat(&b'0'))
+        .take(3)
+        .fold(0u16, |acc, digit| acc * 10 + u16::from(digit - b'0'));
+
+    match int {
+        b"0" => Some(thousandths),
+        b"1" if thousandths == 0 => Some(1000),
+        _ => None
+    }
+}
+
+/// The encodings acceptable to the client, most preferred first.
+#[derive(Debug, Copy, Clone)]
+pub struct Accepted {
+    // the weight of each encoding in `Encoding::ALL`, sorted by weight (stable, so ties keep our preference)
+    weighted: [(Encoding, u16); 3]
+}
+
+impl Accepted {
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = Encoding> + '_ {
+        self.weighted.iter().filter(|(_, q)| *q != 0).map(|(encoding, _)| *encoding)
+    }
+}
+
+/// Negotiate the encodings acceptable to the client from its `Accept-Encoding` headers.
+///
+/// Without the header we only send identity, while the RFC permits any coding in that case, clients which omit it are
+/// rarely prepared for one.
+#[must_use]
+pub fn accepted(headers: &Headers) -> Accepted {
+    let mut explicit: [Option<u16>; 3] = [None; 3];
+    let mut wildcard = None;
+    let mut any = false;
+
+    let elements = headers.get_all("accept-encoding")
+        .flat_map(|value| value.split(|byte| *byte == b','))
+        .map(<[u8]>::trim_ascii)
+        .filter(|element| !element.is_empty());
+
+    for element in elements {
+        any = true;
+        let mut params = element.split(|byte| *byte == b';').map(<[u8]>::trim_ascii);
+        let coding = params.next().unwrap_or_default();
+        let q = params
+            .find_map(|param| param.strip_prefix(b"q=").or_else(|| param.strip_prefix(b"Q=")))
+            .map_or(Some(1000), parse_qvalue);
+        // an invalid weight makes the element meaningless, so it is ignored
+        let Some(q) = q else { continue };
+
+        if coding == b"*" {
+            wildcard = Some(q);
+        } else if let Some(i) = Encoding::ALL.iter().position(|encoding| encoding.matches(coding)) {
+            explicit[i] = Some(q);
+        }
+    }
+
+    let mut weighted = Encoding::ALL.map(|encoding| (encoding, 0));
+    for (i, (encoding, q)) in weighted.iter_mut().enumerate() {
+        *q = match (explicit[i], *encoding) {
+            (Some(q), _) => q,
+            // identity is acceptable unless explicitly excluded, by itself or the wildcard, though with the lowest weight
+            // so that any coding the client lists is preferred.
+            (None, Encoding::Identity) => wildcard.filter(|q| *q == 0).unwrap_or(1),
+            (None, _) if any => wildcard.unwrap_or(0),
+            (None, _) => 0
+        };
+    }
+    weighted.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    Accepted { weighted }
+}
+
+/// Whether responses of this MIME type benefit from compression, most media formats are already compressed.
+#[cfg(feature = "compression")]
+#[inline]
+#[must_use]
+pub fn compressible(mime: &str, len: usize) -> bool {
+    len >= MIN_COMPRESS && (
+        mime.starts_with("text/")
+            || mime.ends_with("javascript")
+            || mime.ends_with("json")
+            || mime.ends_with("xml")
+            || mime == "application/wasm"
+    )
+}
+
+/// Compress `bytes` with `encoding`, favouring speed over ratio as this takes place while serving a request.
+#[cfg(feature = "compression")]
+pub fn compress(encoding: Encoding, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut writer = brotli::CompressorWriter::new(Vec::with_capacity(bytes.len() / 2), 4096, 5, 22);
+            writer.write_all(bytes)?;
+            // finishes the stream
+            Ok(writer.into_inner())
+        },
+        Encoding::Gzip => {
+            let mut writer = flate2::write::GzEncoder::new(
+                Vec::with_capacity(bytes.len() / 2),
+                flate2::Compression::default()
+            );
+            writer.write_all(bytes)?;
+            writer.finish()
+        },
+        Encoding::Identity => Ok(bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted_for(raw: &[&str]) -> Vec<Encoding> {
+        let mut headers = Headers::new();
+        for value in raw {
+            headers.push(b"Accept-Encoding", value.as_bytes());
+        }
+        accepted(&headers).iter().collect()
+    }
+
+    #[test]
+    fn negotiate() {
+        use Encoding::*;
+
+        assert_eq!(accepted_for(&[]), [Identity]);
+        assert_eq!(accepted_for(&["gzip, deflate, br, zstd"]), [Brotli, Gzip, Identity]);
+        assert_eq!(accepted_for(&["gzip", "br;q=0.5"]), [Gzip, Brotli, Identity]);
+        assert_eq!(accepted_for(&["x-gzip; q=0.8, identity;q=0.9"]), [Identity, Gzip]);
+        assert_eq!(accepted_for(&["*"]), [Brotli, Gzip, Identity]);
+        assert_eq!(accepted_for(&["br;q=0, *;q=0.1"]), [Gzip, Identity]);
+        assert_eq!(accepted_for(&["gzip, identity;q=0"]), [Gzip]);
+        assert_eq!(accepted_for(&["gzip, *;q=0"]), [Gzip]);
+        assert_eq!(accepted_for(&["br;q=1.5, gzip;q=0.001"]), [Gzip, Identity]);
+    }
+
+    #[test]
+    fn qvalues() {
+        assert_eq!(parse_qvalue(b"1"), Some(1000));
+        assert_eq!(parse_qvalue(b"1.000"), Some(1000));
+        assert_eq!(parse_qvalue(b"0.5"), Some(500));
+        assert_eq!(parse_qvalue(b"0.25"), Some(250));
+        assert_eq!(parse_qvalue(b"0"), Some(0));
+        assert_eq!(parse_qvalue(b"1.5"), None);
+        assert_eq!(parse_qvalue(b"0.1234"), None);
+        assert_eq!(parse_qvalue(b""), None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn round_trip() {
+        use std::io::Read;
+        let bytes = b"console.log('hello world');\n".repeat(64);
+
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(compress(Encoding::Brotli, &bytes).unwrap().as_slice(), 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, bytes);
+
+        decoded.clear();
+        flate2::read::GzDecoder::new(compress(Encoding::Gzip, &bytes).unwrap().as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, bytes);
+    }
+}
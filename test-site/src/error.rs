@@ -0,0 +1,101 @@
+//! Error responses, as JSON for clients which prefer it, otherwise as the configured page for the status or plain text.
+use crate::accept;
+use crate::encoding::Encoding;
+use crate::lazy_file::Contents;
+use crate::parse::Request;
+use crate::response::{Body, Response, Status};
+use std::io;
+
+/// `EMFILE` and `ENFILE`, which share their numbers across Linux, macOS and the BSDs
+#[cfg(unix)]
+const OUT_OF_FILES: [i32; 2] = [24, 23];
+
+/// The status to answer with when a request could not be served.
+///
+/// Paths we refused to parse are the client's fault, files which resolve outside of the dist directory are forbidden,
+/// and anything missing is not found. Running out of file descriptors is temporary, so worth retrying, while anything
+/// else is our own failure.
+#[must_use]
+pub fn status(err: &io::Error) -> Status {
+    match err.kind() {
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Status::BAD_REQUEST,
+        io::ErrorKind::PermissionDenied => Status::FORBIDDEN,
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => Status::NOT_FOUND,
+        #[cfg(unix)]
+        _ if err.raw_os_error().is_some_and(|code| OUT_OF_FILES.contains(&code)) => Status::SERVICE_UNAVAILABLE,
+        _ => Status::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Whether the error should be described as JSON rather than HTML, which ties favour.
+#[inline]
+#[must_use]
+pub fn wants_json(req: &Request) -> bool {
+    accept::prefers(&req.headers, "application/json", "text/html")
+}
+
+/// `{"status":404,"error":"Not Found"}`
+pub fn json(status: Status) -> Response {
+    let body = format!("{{\"status\":{},\"error\":\"{}\"}}", status.code(), status.reason());
+    finish(Response::new(status, Body::Shared(body.into_bytes().into())), "application/json")
+}
+
+/// Respond with the error page `src` (encoded with `encoding`) in place of the plain text body of `status`.
+pub fn page(status: Status, src: &Contents, encoding: Encoding) -> Response {
+    let res = finish(Response::new(status, Body::Shared(src.bytes().clone())), "text/html")
+        .header("Vary", "Accept-Encoding");
+    match encoding {
+        Encoding::Identity => res,
+        encoding => res.header("Content-Encoding", encoding.as_str())
+    }
+}
+
+/// The plain text reason phrase, when neither JSON nor a page was wanted or available.
+#[inline]
+pub fn plain(status: Status) -> Response {
+    Response::status(status).header("Vary", "Accept").header("Cache-Control", "no-cache")
+}
+
+#[inline]
+fn finish(res: Response, mime: &'static str) -> Response {
+    res.header("Content-Type", mime).header("Vary", "Accept").header("Cache-Control", "no-cache")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses() {
+        let status = |kind: io::ErrorKind| status(&io::Error::new(kind, "test"));
+        assert_eq!(status(io::ErrorKind::InvalidInput), Status::BAD_REQUEST);
+        assert_eq!(status(io::ErrorKind::PermissionDenied), Status::FORBIDDEN);
+        assert_eq!(status(io::ErrorKind::NotFound), Status::NOT_FOUND);
+        assert_eq!(status(io::ErrorKind::Other), Status::INTERNAL_SERVER_ERROR);
+        #[cfg(unix)]
+        assert_eq!(super::status(&io::Error::from_raw_os_error(24)), Status::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn framing() {
+        let write = |res: Response, keep_alive: bool, head_only: bool| {
+            let mut out = Vec::new();
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(res.write_h1(&mut out, keep_alive, head_only))
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let json = write(json(Status::NOT_FOUND), true, false);
+        assert!(json.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(json.contains("Content-Type: application/json\r\n"));
+        assert!(json.contains("Content-Length: 34\r\nConnection: keep-alive\r\n\r\n"));
+        assert!(json.ends_with("\r\n\r\n{\"status\":404,\"error\":\"Not Found\"}"));
+
+        // `HEAD` describes the body it omits
+        let head = write(plain(Status::INTERNAL_SERVER_ERROR), false, true);
+        assert!(head.ends_with("Content-Length: 21\r\nConnection: close\r\n\r\n"));
+    }
+}
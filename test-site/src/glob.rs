@@ -0,0 +1,146 @@
+//! Glob patterns for matching request paths in the config file.
+//!
+//! `*` matches any characters within a segment, `**` any characters including `/`, and `?` a single character other
+//! than `/`. A whole segment of `**` (`/**/`) may also match no segments at all, so `/**/sw.js` matches `/sw.js`.
+//! Everything else matches itself.
+use serde::Deserialize;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    /// `?`
+    One,
+    /// `*`
+    Star,
+    /// `**`
+    Deep,
+    /// `**/` following a `/`, any number of whole segments including none
+    Dirs
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Glob {
+    raw: String,
+    tokens: Vec<Token>
+}
+
+impl Glob {
+    pub fn new(raw: &str) -> io::Result<Self> {
+        if raw.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Glob patterns may not be empty"));
+        }
+
+        let mut tokens = Vec::new();
+        let mut chars = raw.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' if chars.next_if_eq(&'*').is_some() => {
+                    // any further stars are redundant
+                    while chars.next_if_eq(&'*').is_some() {}
+                    let after_slash = matches!(tokens.last(), Some(Token::Literal(literal)) if literal.ends_with('/'));
+                    if after_slash && chars.next_if_eq(&'/').is_some() {
+                        Token::Dirs
+                    } else {
+                        Token::Deep
+                    }
+                },
+                '*' => Token::Star,
+                '?' => Token::One,
+                c => {
+                    if let Some(Token::Literal(literal)) = tokens.last_mut() {
+                        literal.push(c);
+                        continue;
+                    }
+                    Token::Literal(c.to_string())
+                }
+            };
+            tokens.push(token);
+        }
+
+        Ok(Self { raw: raw.to_owned(), tokens })
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn matches(&self, text: &str) -> bool {
+        matches_from(&self.tokens, text)
+    }
+}
+
+impl TryFrom<String> for Glob {
+    type Error = io::Error;
+
+    #[inline]
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        Self::new(&raw)
+    }
+}
+
+fn matches_from(tokens: &[Token], text: &str) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return text.is_empty();
+    };
+
+    match token {
+        Token::Literal(literal) => text.strip_prefix(literal.as_str()).is_some_and(|text| matches_from(rest, text)),
+        Token::One => {
+            let mut chars = text.chars();
+            chars.next().is_some_and(|c| c != '/') && matches_from(rest, chars.as_str())
+        },
+        Token::Star | Token::Deep => {
+            // the most we may consume, stars stop at the end of the segment
+            let end = match token {
+                Token::Star => text.find('/').unwrap_or(text.len()),
+                _ => text.len()
+            };
+            (0..=end).filter(|i| text.is_char_boundary(*i)).any(|i| matches_from(rest, &text[i..]))
+        },
+        Token::Dirs => (0..=text.len())
+            .filter(|i| *i == 0 || text.as_bytes()[i - 1] == b'/')
+            .any(|i| matches_from(rest, &text[i..]))
+    }
+}
+
+/// Whether `text` matches any of `globs`
+#[inline]
+#[must_use]
+pub fn any(globs: &[Glob], text: &str) -> bool {
+    globs.iter().any(|glob| glob.matches(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let glob = |raw: &str| Glob::new(raw).unwrap();
+
+        assert!(glob("/**").matches("/"));
+        assert!(glob("/**").matches("/users/42/settings"));
+        assert!(glob("/app/*").matches("/app/users"));
+        assert!(!glob("/app/*").matches("/app/users/42"));
+        assert!(glob("/app/**").matches("/app/users/42"));
+        assert!(!glob("/app/**").matches("/application"));
+        assert!(glob("/api/v?/**").matches("/api/v2/users"));
+        assert!(!glob("/api/v?/**").matches("/api/v10/users"));
+        assert!(glob("*.js").matches("bundle.min.js"));
+        assert!(!glob("*.js").matches("assets/bundle.js"));
+        assert!(glob("**.js").matches("assets/bundle.js"));
+        assert!(glob("/caf\u{e9}/*").matches("/caf\u{e9}/menu"));
+        assert!(glob("text/*").matches("text/html"));
+        assert!(glob("/**/sw.js").matches("/sw.js"));
+        assert!(glob("/**/sw.js").matches("/app/v2/sw.js"));
+        assert!(!glob("/**/sw.js").matches("/appsw.js"));
+        assert!(glob("/app/**/*.js").matches("/app/main.js"));
+        assert!(Glob::new("").is_err());
+    }
+}
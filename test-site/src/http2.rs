@@ -0,0 +1,370 @@
+//! HTTP/2 connections, negotiated through ALPN under TLS or opened with prior knowledge (h2c) on plain TCP.
+//!
+//! Framing, HPACK and flow control are left to the `h2` crate, each stream is adapted into the same [`Request`] and
+//! [`Response`](crate::response::Response) as HTTP/1, so both are served from the same routes and file cache.
+use crate::conf::HttpConf;
+use crate::parse::{Headers, Method, Request, Version};
+use crate::response::Body;
+use crate::Handler;
+use bytes::Bytes;
+use h2::server::{self, SendResponse};
+use h2::{Reason, RecvStream, SendStream};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use std::io::{self, IoSlice};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::timeout;
+use tracing::{instrument, trace, debug, Level};
+
+/// The client connection preface, which h2c clients with prior knowledge open the connection with.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// HTTP/1 headers which are specific to a connection, and therefore malformed in HTTP/2
+const CONNECTION_SPECIFIC: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// A stream which first yields the bytes which were already read from it, so that a connection which turned out to be
+/// HTTP/2 can be handed over without losing the start of the preface.
+pub struct Rewind<S> {
+    read: Vec<u8>,
+    pos: usize,
+    inner: S
+}
+
+impl<S> Rewind<S> {
+    #[inline]
+    pub const fn new(read: Vec<u8>, inner: S) -> Self {
+        Self { read, pos: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pos < self.read.len() {
+            let len = core::cmp::min(buf.remaining(), self.read.len() - self.pos);
+            buf.put_slice(&self.read[self.pos..self.pos + len]);
+            self.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    #[inline]
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[inline]
+fn into_io(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        err.into_io().unwrap(/* infallible, we just checked */)
+    } else {
+        io::Error::other(err)
+    }
+}
+
+impl From<Body> for Bytes {
+    #[inline]
+    fn from(body: Body) -> Self {
+        match body {
+            Body::Empty => Self::new(),
+            Body::Static(bytes) => Self::from_static(bytes),
+            Body::Shared(bytes) => Self::from_owner(bytes),
+            Body::Slice(bytes, range) => Self::from_owner(bytes).slice(range),
+            // streamed separately, see `send_events`
+            #[cfg(feature = "reload")]
+            Body::Events(_) => Self::new()
+        }
+    }
+}
+
+#[instrument(
+    name = "h2-connection",
+    skip_all,
+    err(Debug, level = Level::DEBUG),
+    level = Level::DEBUG
+)]
+pub async fn serve<S>(stream: S, handler: Handler, conf: &'static HttpConf) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(conf.max_concurrent_streams)
+        .max_header_list_size(u32::try_from(conf.max_header_size).unwrap_or(u32::MAX))
+        .handshake(stream);
+    let mut conn = match timeout(conf.read_timeout(), handshake).await {
+        Ok(conn) => conn.map_err(into_io)?,
+        Err(_) => return Err(io::ErrorKind::TimedOut.into())
+    };
+
+    let mut served = 0usize;
+    let mut closing = false;
+
+    loop {
+        // once closing, the connection only needs driving until the streams in flight complete
+        let next = if closing {
+            conn.accept().await
+        } else {
+            match timeout(conf.keep_alive_timeout(), conn.accept()).await {
+                Ok(next) => next,
+                // only idle once every stream completed, which event streams may well never do
+                Err(_) if conn.has_streams() => continue,
+                Err(_) => {
+                    debug!("No new streams for {}s, closing", conf.keep_alive_timeout);
+                    conn.graceful_shutdown();
+                    closing = true;
+                    continue;
+                }
+            }
+        };
+
+        match next {
+            Some(Ok((req, send))) => {
+                served += 1;
+                trace!("Serving stream {served} on this connection");
+                if served >= conf.max_requests && !closing {
+                    conn.graceful_shutdown();
+                    closing = true;
+                }
+                // streams are multiplexed, so each is served concurrently with the others
+                tokio::spawn(respond(req, send, handler));
+            },
+            Some(Err(err)) => return Err(into_io(err)),
+            None => return Ok(())
+        }
+    }
+}
+
+/// Adapt the head of an HTTP/2 request into a [`Request`], `:authority` standing in for `Host`.
+fn request(parts: &http::request::Parts) -> Request<'_> {
+    let method = Method::from_bytes(parts.method.as_str().as_bytes()).unwrap_or(Method::Extension);
+    let target = parts.uri.path_and_query().map_or(b"/".as_slice(), |target| target.as_str().as_bytes());
+
+    let mut headers = Headers::new();
+    if let Some(authority) = parts.uri.authority() {
+        if !parts.headers.contains_key(http::header::HOST) {
+            headers.push(b"host", authority.as_str().as_bytes());
+        }
+    }
+    for (name, value) in &parts.headers {
+        headers.push(name.as_str().as_bytes(), value.as_bytes());
+    }
+
+    Request { method, target, version: Version::Http2, headers, len: 0 }
+}
+
+#[instrument(
+    name = "stream",
+    skip_all,
+    fields(id = ?send.stream_id()),
+    level = Level::DEBUG
+)]
+async fn respond(req: http::Request<RecvStream>, mut send: SendResponse<Bytes>, handler: Handler) {
+    // like HTTP/1 we've no use for request bodies, dropping the stream tells the client to stop sending
+    let (parts, _) = req.into_parts();
+    let req = request(&parts);
+    let head_only = req.method == Method::Head;
+    let res = handler.respond(&req).await;
+
+    let mut head = http::Response::builder().status(res.status.code());
+    for (name, value) in &res.headers {
+        if !CONNECTION_SPECIFIC.iter().any(|specific| name.eq_ignore_ascii_case(specific)) {
+            head = head.header(*name, value.as_ref());
+        }
+    }
+    let bodiless = res.status.is_bodiless();
+    if !bodiless && !res.body.is_streamed() {
+        head = head.header("content-length", res.body.len());
+    }
+
+    let head = match head.body(()) {
+        Ok(head) => head,
+        Err(err) => {
+            debug!("Invalid response head: {err}");
+            send.send_reset(Reason::INTERNAL_ERROR);
+            return;
+        }
+    };
+
+    // subscribed before the head is sent, so that no event is missed in between
+    #[cfg(feature = "reload")]
+    let events = match &res.body {
+        Body::Events(events) if !head_only => Some(events.subscribe()),
+        _ => None
+    };
+
+    let end = head_only || bodiless || (res.body.len() == 0 && !res.body.is_streamed());
+    let sent = match send.send_response(head, end) {
+        #[cfg(feature = "reload")]
+        Ok(stream) if !end && events.is_some() => {
+            send_events(stream, events.unwrap(/* infallible, just checked */)).await
+        },
+        Ok(stream) if !end => send_body(stream, res.body.into()).await,
+        Ok(_) => Ok(()),
+        Err(err) => Err(err)
+    };
+    if let Err(err) = sent {
+        debug!("Failed to send the response: {err}");
+    }
+}
+
+/// Send `body` as the peer grants us capacity, rather than buffering all of it in the connection.
+async fn send_body(mut stream: SendStream<Bytes>, mut body: Bytes) -> Result<(), h2::Error> {
+    while !body.is_empty() {
+        stream.reserve_capacity(body.len());
+        match core::future::poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(Ok(capacity)) => {
+                let chunk = body.split_to(core::cmp::min(capacity, body.len()));
+                stream.send_data(chunk, body.is_empty())?;
+            },
+            Some(Err(err)) => return Err(err),
+            // the stream was reset by the client
+            None => return Ok(())
+        }
+    }
+    Ok(())
+}
+
+/// Stream `events` until either side gives up on them.
+#[cfg(feature = "reload")]
+async fn send_events(mut stream: SendStream<Bytes>, mut events: crate::live::Receiver) -> Result<(), h2::Error> {
+    stream.send_data(Bytes::from_static(crate::live::PREAMBLE), false)?;
+    // events are tiny, so they are left to the connection's buffer rather than waiting on capacity
+    while let Some(event) = crate::live::next(&mut events).await {
+        stream.send_data(Bytes::from_owner(event), false)?;
+    }
+    stream.send_data(Bytes::new(), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::ServerConf;
+    use crate::Site;
+    use std::time::Duration;
+
+    #[test]
+    fn multiplexed_h2c() {
+        let index = std::env::temp_dir().join(format!("test-site-h2-{}.html", std::process::id()));
+        std::fs::write(&index, "<h1>hello</h1>").unwrap();
+
+        let conf: &'static ServerConf = Box::leak(Box::new(ServerConf::for_tests(index.to_str().unwrap())));
+        let site: &'static Site = Box::leak(Box::new(Site::new(conf).unwrap()));
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                let (client, server) = tokio::io::duplex(1024);
+                // served as a plain connection, so the preface must be detected
+                tokio::spawn(crate::conn::serve(server, Handler::Site(site), &conf.http));
+
+                let (client, conn) = h2::client::handshake(client).await.unwrap();
+                tokio::spawn(conn);
+
+                let mut pending = Vec::new();
+                for method in ["GET", "HEAD", "DELETE"] {
+                    let req = http::Request::builder()
+                        .method(method)
+                        .uri("http://localhost/")
+                        .body(())
+                        .unwrap();
+                    let (res, _) = client.clone().ready().await.unwrap().send_request(req, true).unwrap();
+                    pending.push(res);
+                }
+
+                let mut statuses = Vec::new();
+                for res in pending {
+                    let res = tokio::time::timeout(Duration::from_secs(5), res).await.unwrap().unwrap();
+                    statuses.push(res.status().as_u16());
+
+                    let len = res.headers().get("content-length").cloned();
+                    let mut body = res.into_body();
+                    let mut received = Vec::new();
+                    while let Some(chunk) = body.data().await {
+                        received.extend_from_slice(&chunk.unwrap());
+                    }
+                    if statuses.len() == 1 {
+                        assert_eq!(received, b"<h1>hello</h1>");
+                        assert_eq!(len.unwrap(), "14");
+                    } else if statuses.len() == 2 {
+                        assert!(received.is_empty());
+                        assert_eq!(len.unwrap(), "14");
+                    }
+                }
+                assert_eq!(statuses, [200, 200, 405]);
+
+                // and the connection is still usable once they have completed
+                let req = http::Request::builder().uri("http://localhost/").body(()).unwrap();
+                let (res, _) = client.ready().await.unwrap().send_request(req, true).unwrap();
+                assert_eq!(res.await.unwrap().status(), 200);
+            });
+
+        let _ = std::fs::remove_file(index);
+    }
+
+    #[cfg(feature = "reload")]
+    #[test]
+    fn events_outlive_keep_alive() {
+        let index = std::env::temp_dir().join(format!("test-site-h2-events-{}.html", std::process::id()));
+        std::fs::write(&index, "<h1>hello</h1>").unwrap();
+
+        let mut conf = ServerConf::for_tests(index.to_str().unwrap());
+        conf.http.keep_alive_timeout = 1;
+        conf.live_reload = Some(crate::conf::LiveReloadConf::default());
+        let conf: &'static ServerConf = Box::leak(Box::new(conf));
+        let site: &'static Site = Box::leak(Box::new(Site::new(conf).unwrap()));
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                let (client, server) = tokio::io::duplex(1024);
+                tokio::spawn(crate::conn::serve(server, Handler::Site(site), &conf.http));
+                let (client, conn) = h2::client::handshake(client).await.unwrap();
+                tokio::spawn(conn);
+
+                let req = http::Request::builder().uri("http://localhost/__live-reload").body(()).unwrap();
+                let (res, _) = client.clone().ready().await.unwrap().send_request(req, true).unwrap();
+                let mut events = res.await.unwrap().into_body();
+                assert_eq!(&events.data().await.unwrap().unwrap()[..], crate::live::PREAMBLE);
+
+                // the open stream keeps the connection from counting as idle
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+                site.live.unwrap().notify(&["/app.js".to_owned()]);
+                let event = tokio::time::timeout(Duration::from_secs(5), events.data()).await.unwrap();
+                assert!(event.unwrap().unwrap().starts_with(b"event: reload\n"));
+
+                let req = http::Request::builder().uri("http://localhost/").body(()).unwrap();
+                let (res, _) = client.ready().await.unwrap().send_request(req, true).unwrap();
+                assert_eq!(res.await.unwrap().status(), 200);
+            });
+
+        let _ = std::fs::remove_file(index);
+    }
+}
@@ -1,12 +1,18 @@
 use std::io;
 use core::cell::UnsafeCell;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::path::{Path, PathBuf};
-use tokio::io::{AsyncWriteExt};
-use tokio::fs;
-use tokio::net::TcpStream;
-use core::ops::DerefMut;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::time::SystemTime;
+#[cfg(feature = "compression")]
+use tokio::sync::OnceCell;
+#[cfg(feature = "compression")]
+use crate::encoding::{self, Encoding};
 use tracing::{trace, debug};
+#[cfg(feature = "compression")]
+use tracing::warn;
 
 #[cfg(not(feature = "reload"))]
 use core::sync::atomic::{AtomicBool, Ordering};
@@ -69,8 +75,125 @@ impl LazyFileInner {
     }
 }
 
-struct Sendable<T>(T);
-unsafe impl<T> Send for Sendable<T> {}
+/// The contents of a file along with its validators, computed once per load.
+#[derive(Debug)]
+pub struct Contents {
+    bytes: Arc<[u8]>,
+    etag: String,
+    last_modified: Option<String>,
+    modified: Option<SystemTime>,
+    // compressed on first use, then held until the contents are replaced. `None` if compressing did not pay off.
+    #[cfg(feature = "compression")]
+    encoded: [OnceCell<Option<Src>>; 2]
+}
+
+impl Contents {
+    #[must_use]
+    pub fn new(bytes: Vec<u8>, modified: Option<SystemTime>) -> Self {
+        // `DefaultHasher` is not guaranteed to be stable between releases of std, though it is for the lifetime of
+        // the binary, which is all a development server needs.
+        let mut hasher = std::hash::DefaultHasher::new();
+        bytes.hash(&mut hasher);
+
+        Self {
+            etag: format!("\"{:x}-{:016x}\"", bytes.len(), hasher.finish()),
+            last_modified: modified.map(httpdate::fmt_http_date),
+            modified,
+            bytes: Arc::from(bytes),
+            #[cfg(feature = "compression")]
+            encoded: [OnceCell::new(), OnceCell::new()]
+        }
+    }
+
+    /// Read the file at `path`, which when `exact` must be where the file opened actually is.
+    fn read(path: &Path, exact: bool) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        if exact && opened_path(&file, path)? != path {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("`{}` no longer resolves to itself, a symlink was swapped in along it", path.display())
+            ));
+        }
+        let modified = file.metadata().and_then(|meta| meta.modified()).ok();
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(Self::new(bytes, modified))
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn bytes(&self) -> &Arc<[u8]> {
+        &self.bytes
+    }
+
+    /// The strong entity tag of the contents, derived from their hash
+    #[inline]
+    #[must_use]
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    /// The modification time of the file formatted as an HTTP-date
+    #[inline]
+    #[must_use]
+    pub fn last_modified(&self) -> Option<&str> {
+        self.last_modified.as_deref()
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// The contents compressed with `encoding`, compressing them on first use.
+    ///
+    /// Compressing a large file takes a while, so it happens on tokio's blocking threads, other requests for the same
+    /// encoding waiting for it. `None` for identity, or if compression failed or did not make the contents any smaller.
+    #[cfg(feature = "compression")]
+    pub async fn encoded(&self, encoding: Encoding) -> Option<Src> {
+        let slot = match encoding {
+            Encoding::Brotli => &self.encoded[0],
+            Encoding::Gzip => &self.encoded[1],
+            Encoding::Identity => return None
+        };
+        slot.get_or_init(|| async {
+            let (bytes, modified) = (self.bytes.clone(), self.modified);
+            let compressed = tokio::task::spawn_blocking(move || match encoding::compress(encoding, &bytes) {
+                Ok(compressed) if compressed.len() < bytes.len() => {
+                    debug!("Compressed with {}, {} -> {} bytes", encoding.as_str(), bytes.len(), compressed.len());
+                    Some(Src::new(Self::new(compressed, modified)))
+                },
+                Ok(_) => None,
+                Err(err) => {
+                    warn!("Failed to compress with {}: {err:?}", encoding.as_str());
+                    None
+                }
+            }).await;
+            compressed.unwrap_or_else(|err| {
+                warn!("Failed to compress with {}: {err:?}", encoding.as_str());
+                None
+            })
+        }).await.clone()
+    }
+}
+
+/// Where `file`, opened from `path`, actually is once symlinks are resolved.
+#[cfg(target_os = "linux")]
+fn opened_path(file: &fs::File, path: &Path) -> io::Result<PathBuf> {
+    use std::os::fd::AsRawFd;
+    // asking after the file we opened rather than the path leaves no window for the path to change in between
+    fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).or_else(|_| fs::canonicalize(path))
+}
+
+/// Where `file`, opened from `path`, actually is once symlinks are resolved.
+#[cfg(not(target_os = "linux"))]
+fn opened_path(_file: &fs::File, path: &Path) -> io::Result<PathBuf> {
+    fs::canonicalize(path)
+}
+
+/// A snapshot of the file's contents, cheap to clone and unaffected by later reloads.
+pub type Src = Arc<Contents>;
 
 pub struct LazyFile {
     // use std sync as the state transition will only occur once, avoids context switching. std mutex is an adaptive
@@ -79,9 +202,19 @@ pub struct LazyFile {
     // implementations diverge from the theory in the name of performance.
     inner: Mutex<LazyFileInner>,
 
+    /// Held while the file is first read, off the async workers, so that other requests for it wait without blocking
+    /// their worker and the watcher does not refresh it halfway through.
+    loading: tokio::sync::Mutex<()>,
+
     // we guard this with the inner mutex, I generally do not like relying on other sync, but this is completely fine.
     // I simply want to avoid holding the mutex guard longer than needed.
-    src: UnsafeCell<Option<Vec<u8>>>,
+    src: UnsafeCell<Option<Src>>,
+
+    /// Where the file is read from, again whenever it is refreshed
+    #[cfg(feature = "reload")]
+    path: PathBuf,
+    /// Whether `path` was resolved, so that the file must be found exactly there each time it is read
+    exact: bool,
 
     #[cfg(not(feature = "reload"))]
     loaded: CachePadded<AtomicBool>
@@ -98,7 +231,7 @@ macro_rules! poison_err {
 
 impl LazyFile {
     #[inline(always)]
-    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    pub fn new<P: AsRef<Path>>(path: P, exact: bool) -> io::Result<Self> {
         trace!("Attempting to create `LazyFile`, checking if path exists...");
         // We must check existence synchronously, as otherwise the radix trie would require sync which would outweigh 
         // the benefits especially considering filesystem caching. 
@@ -106,7 +239,11 @@ impl LazyFile {
             trace!("Path existed, creating `LazyFile` in the `Pending` state");
             Ok(Self {
                 inner: std::sync::Mutex::new(LazyFileInner::Pending(path.as_ref().to_path_buf())),
+                loading: tokio::sync::Mutex::new(()),
                 src: UnsafeCell::new(None),
+                #[cfg(feature = "reload")]
+                path: path.as_ref().to_path_buf(),
+                exact,
                 #[cfg(not(feature = "reload"))]
                 loaded: CachePadded(AtomicBool::new(false))
             })
@@ -117,7 +254,7 @@ impl LazyFile {
     }
 
     #[cfg(feature = "reload")]
-    pub fn replace_src(&self, new: Vec<u8>) -> io::Result<()> {
+    pub fn replace_src(&self, new: Contents) -> io::Result<()> {
         // we hold our guard to prevent other threads from reading during the swap. 
         // Reloads are only used in dev servers where contention will be low.
         let Ok(mut guard) = self.inner.lock() else { return Err( poison_err!() ) };
@@ -126,63 +263,114 @@ impl LazyFile {
         guard.pending_swap();
 
         // SAFETY: We are holding the guard which prevents any usage of the bytes until dropped.
-        unsafe { core::ptr::replace(self.src.get(), Some(new)); }
+        unsafe { core::ptr::replace(self.src.get(), Some(Src::new(new))); }
 
         drop(guard);
         
         Ok(())
     }
 
-    #[inline(always)]
-    fn send_lock(&self) -> io::Result<Sendable<std::sync::MutexGuard<'_, LazyFileInner>>> {
-        self.inner.lock().map(Sendable).map_err(|_| poison_err!())
+    /// Re-read the file if it changed since it was loaded, returning whether the contents were replaced.
+    ///
+    /// Changes are told apart by the modification time, to the nanosecond, and the size, which are cheap to compare.
+    /// If these differ though the contents hash the same (a `touch`, or a build rewriting an unchanged file) the
+    /// current contents are kept, along with their `ETag` and anything compressed from them.
+    #[cfg(feature = "reload")]
+    pub fn refresh(&self) -> io::Result<bool> {
+        let path = self.path.as_path();
+        // called from the watcher's thread, which may wait on a first read still in progress
+        let _loading = self.loading.blocking_lock();
+        let current = {
+            let Ok(state) = self.inner.lock() else { return Err( poison_err!() ) };
+            if matches!(*state, LazyFileInner::Pending(_)) {
+                // never loaded, so it will be read fresh on first use anyway
+                return Ok(false);
+            }
+            // SAFETY: We are holding the guard, and the contents are always present once no longer pending.
+            match unsafe { &*self.src.get() } {
+                Some(src) => src.clone(),
+                None => return Ok(false)
+            }
+        };
+
+        let meta = fs::metadata(path)?;
+        if meta.modified().ok() == current.modified && meta.len() == current.bytes.len() as u64 {
+            trace!("Neither the modification time nor the size changed, keeping the contents");
+            return Ok(false);
+        }
+
+        let fresh = Contents::read(path, self.exact)?;
+        if fresh.etag == current.etag {
+            debug!("The file was rewritten with the same contents, keeping them");
+            return Ok(false);
+        }
+        self.replace_src(fresh).map(|()| true)
     }
 
+    /// Get the contents of the file, reading it on first use.
+    ///
+    /// The initial read happens on tokio's blocking threads, so that neither reading nor hashing a large file holds up
+    /// the other connections of this worker. Concurrent requests wait for that read rather than repeating it.
     #[allow(unreachable_code)]
-    pub async fn write(&self, dst: &mut TcpStream) -> io::Result<()> {
-        // We only need to synchronize our getting of the slice if reload is enabled. As otherwise after initial call
-        // the source is never mutated.
-        macro_rules! unchecked_write {
-            ($this:ident, $dst:ident) => {{
-                let __src = match unsafe { & *$this.src.get() } {
-                    Some(__inner) => __inner.as_slice(),
+    pub async fn load(&self) -> io::Result<Src> {
+        // We only need to synchronize our getting of the contents if reload is enabled. As otherwise after initial
+        // call the source is never mutated.
+        macro_rules! unchecked_get {
+            ($this:ident) => {{
+                match unsafe { & *$this.src.get() } {
+                    Some(__inner) => __inner.clone(),
                     None => unreachable!()
-                };
-                
-                $dst.write_all(__src).await
+                }
             }}
         }
         #[cfg(not(feature = "reload"))] {
-            if self.loaded.0.load(Ordering::Relaxed) {
-                return unchecked_write!(self, dst);
+            if self.loaded.0.load(Ordering::Acquire) {
+                return Ok(unchecked_get!(self));
             }
         }
-        // we check our state, if we must load we hold our guard
-        {
-            let Ok(mut state) = self.send_lock() else { return Err( poison_err!() ) };
-            if let Some(path) = state.0.deref_mut().pending_swap() {
-                let file = fs::read(path).await?;
-                let src = unsafe { &mut *self.src.get() };
+        // a std mutex must not be held across an `.await`, so the state is only locked to look at it and, once read,
+        // to store the contents. Until then it stays pending.
+        let _loading = self.loading.lock().await;
+        let pending = {
+            let Ok(state) = self.inner.lock() else { return Err( poison_err!() ) };
+            match &*state {
+                LazyFileInner::Pending(path) => Some(path.clone()),
+                LazyFileInner::Ready => None
+            }
+        };
 
-                debug_assert!(src.is_none(), "Bytes were not none when lazy file state was pending");
+        if let Some(path) = pending {
+            let exact = self.exact;
+            // leaving the file pending on failure, so that the next request may retry
+            let file = tokio::task::spawn_blocking(move || Contents::read(&path, exact))
+                .await
+                .map_err(io::Error::other)??;
 
-                *src = Some(file);
+            let Ok(mut state) = self.inner.lock() else { return Err( poison_err!() ) };
+            state.pending_swap();
+            let src = unsafe { &mut *self.src.get() };
 
-                #[cfg(not(feature = "reload"))] {
-                    self.loaded.0.store(true, Ordering::Release);
-                }
+            debug_assert!(src.is_none(), "Bytes were not none when lazy file state was pending");
+
+            *src = Some(Src::new(file));
+
+            #[cfg(not(feature = "reload"))] {
+                self.loaded.0.store(true, Ordering::Release);
             }
 
             #[cfg(feature = "reload")] {
-                unchecked_write!(self, dst)?;
-                return Ok(());
+                return Ok(unchecked_get!(self));
             }
         }
 
+        #[cfg(feature = "reload")] {
+            let Ok(_state) = self.inner.lock() else { return Err( poison_err!() ) };
+            return Ok(unchecked_get!(self));
+        }
+
         // We do not have the feature `reload` enabled if we got to this point, therefore we do not need any
         // synchronization as the `bytes` will never be mutated.
-        unchecked_write!(self, dst)?;
-        Ok(())
+        Ok(unchecked_get!(self))
     }
 }
 
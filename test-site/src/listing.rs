@@ -0,0 +1,163 @@
+//! Generated listings of directories without an index file, as HTML or (by `Accept`) JSON.
+//!
+//! Listings are read fresh for each request, so they always reflect the latest build.
+use crate::accept;
+use crate::conditional::secs;
+use crate::parse::{percent_decode, Request};
+use crate::response::{Body, Response, Status};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::fs;
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    /// The file name, with a trailing slash for directories
+    name: String,
+    /// Size in bytes, `None` for directories
+    size: Option<u64>,
+    /// Seconds since the unix epoch
+    modified: Option<u64>,
+    /// `None` for directories and unknown extensions
+    mime: Option<&'static str>
+}
+
+async fn read_entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut read = fs::read_dir(dir).await?;
+    let mut entries = Vec::new();
+
+    while let Some(entry) = read.next_entry().await? {
+        // follows symlinks, which are listed as what they point to. Dangling ones are skipped.
+        let Ok(meta) = fs::metadata(entry.path()).await else { continue };
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        let modified = meta.modified().ok().map(secs);
+
+        entries.push(if meta.is_dir() {
+            name.push('/');
+            Entry { name, size: None, modified, mime: None }
+        } else {
+            let mime = Path::new(&name).extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(crate::mime::from_ext);
+            Entry { name, size: Some(meta.len()), modified, mime }
+        });
+    }
+
+    // directories first, then by name
+    entries.sort_by(|a, b| b.name.ends_with('/').cmp(&a.name.ends_with('/')).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// Escape text for HTML content and attribute values.
+fn escape(raw: &str) -> Cow<'_, str> {
+    if !raw.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(raw);
+    }
+    let mut escaped = String::with_capacity(raw.len() + 16);
+    for c in raw.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Percent-encode a file name so that it may be used as a relative URL, only unreserved characters (and the trailing
+/// slash of directories) are left as they are.
+fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    let (name, slash) = match name.strip_suffix('/') {
+        Some(name) => (name, "/"),
+        None => (name, "")
+    };
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            byte => { let _ = write!(encoded, "%{byte:02X}"); }
+        }
+    }
+    encoded.push_str(slash);
+    encoded
+}
+
+fn html(title: &str, entries: &[Entry]) -> String {
+    let title = escape(title);
+    let mut page = String::with_capacity(512 + entries.len() * 160);
+
+    // writing to a `String` is infallible
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n<style>\
+        body{{font-family:system-ui,sans-serif;margin:2em}}td,th{{padding:.2em 1.5em .2em 0;text-align:left}}\
+        td:nth-child(2){{text-align:right}}</style>\n</head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n\
+        <tr><th>Name</th><th>Size</th><th>Modified</th><th>Type</th></tr>\n"
+    );
+    if title != "/" {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let modified = entry.modified
+            .map(|secs| httpdate::fmt_http_date(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)))
+            .unwrap_or_default();
+        let _ = writeln!(
+            page,
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{modified}</td><td>{}</td></tr>",
+            encode(&entry.name),
+            escape(&entry.name),
+            entry.size.map(|size| size.to_string()).unwrap_or_default(),
+            entry.mime.unwrap_or_default()
+        );
+    }
+
+    page.push_str("</table>\n</body>\n</html>\n");
+    page
+}
+
+/// List `dir`, requested as the target of `req`, as JSON if the client prefers it otherwise as HTML.
+pub async fn respond(dir: &Path, req: &Request<'_>) -> io::Result<Response> {
+    let entries = read_entries(dir).await?;
+
+    let (body, mime) = if accept::prefers(&req.headers, "application/json", "text/html") {
+        (serde_json::to_vec(&entries).map_err(io::Error::other)?, "application/json")
+    } else {
+        let path = req.origin_path();
+        let title = percent_decode(path).unwrap_or(Cow::Borrowed(path));
+        (html(&String::from_utf8_lossy(&title), &entries).into_bytes(), "text/html; charset=utf-8")
+    };
+
+    Ok(Response::new(Status::OK, Body::Shared(body.into()))
+        .header("Content-Type", mime)
+        .header("Vary", "Accept")
+        .header("Cache-Control", "no-cache"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders() {
+        let entries = [
+            Entry { name: "assets/".to_owned(), size: None, modified: Some(0), mime: None },
+            Entry { name: "a <b>.js".to_owned(), size: Some(12), modified: None, mime: Some("text/javascript") }
+        ];
+        let page = html("/build/", &entries);
+        assert!(page.contains("<a href=\"assets/\">assets/</a>"));
+        assert!(page.contains(
+            "<a href=\"a%20%3Cb%3E.js\">a &lt;b&gt;.js</a></td><td>12</td><td></td><td>text/javascript</td>"
+        ));
+        assert!(page.contains("<a href=\"../\">"));
+
+        let json = serde_json::to_string(&entries[1]).unwrap();
+        assert_eq!(json, r#"{"name":"a <b>.js","size":12,"modified":null,"mime":"text/javascript"}"#);
+    }
+}
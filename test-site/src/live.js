@@ -0,0 +1,18 @@
+// Reloads the page when the files it was built from change, or updates the service workers when only they changed.
+(() => {
+    const events = new EventSource("{path}");
+    let lost = false;
+
+    // the server restarted, so whatever changed in the meantime was missed
+    events.addEventListener("open", () => lost && location.reload());
+    events.addEventListener("error", () => lost = true);
+
+    events.addEventListener("reload", () => location.reload());
+    events.addEventListener("sw-update", async () => {
+        if (!("serviceWorker" in navigator)) {
+            return;
+        }
+        const registrations = await navigator.serviceWorker.getRegistrations();
+        await Promise.all(registrations.map((registration) => registration.update()));
+    });
+})();
@@ -0,0 +1,149 @@
+//! Live reloading of pages in the browser, with the `reload` feature.
+//!
+//! Pages subscribe to changes over server-sent events, through a small script we serve alongside them and, if asked,
+//! inject into the index file. Changes to service workers alone update their registration, anything else reloads the
+//! page.
+use crate::conf::LiveReloadConf;
+use crate::parse::{Method, Request};
+use crate::response::{Body, Response, Status};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info};
+
+/// How long a stream may go without an event before we send a comment, so that closed connections are noticed
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// Events a page may fall behind by before it is simply reloaded
+const BACKLOG: usize = 16;
+
+/// Sent first, so that pages reconnect quickly once the server is restarted
+pub const PREAMBLE: &[u8] = b"retry: 1000\n\n";
+
+const CLIENT: &str = include_str!("live.js");
+
+/// Where the encoded events are sent, each page subscribing for as long as it is open.
+pub type Events = broadcast::Sender<Arc<[u8]>>;
+/// A page's subscription to [`Events`]
+pub type Receiver = broadcast::Receiver<Arc<[u8]>>;
+
+pub struct LiveReload {
+    conf: &'static LiveReloadConf,
+    events: Events,
+    /// Where the client script is served, the path of the events with `.js` appended
+    script: String,
+    /// The client script, subscribing to the path of the events
+    client: String,
+    /// The tag loading the client script
+    tag: String
+}
+
+impl LiveReload {
+    #[must_use]
+    pub fn new(conf: &'static LiveReloadConf) -> Self {
+        let script = format!("{}.js", conf.path);
+        Self {
+            conf,
+            events: broadcast::channel(BACKLOG).0,
+            client: CLIENT.replace("{path}", &conf.path),
+            tag: format!("<script src=\"{script}\" defer></script>"),
+            script
+        }
+    }
+
+    /// Answer `req` if it is for the events or the client script.
+    pub fn respond(&'static self, req: &Request) -> Option<Response> {
+        if !matches!(req.method, Method::Get | Method::Head) {
+            return None;
+        }
+
+        let path = req.origin_path();
+        if path == self.conf.path.as_bytes() {
+            Some(Response::new(Status::OK, Body::Events(&self.events))
+                .header("Content-Type", "text/event-stream")
+                .header("Cache-Control", "no-store"))
+        } else if path == self.script.as_bytes() {
+            Some(Response::new(Status::OK, Body::Static(self.client.as_bytes()))
+                .header("Content-Type", "text/javascript; charset=utf-8")
+                .header("Cache-Control", "no-cache"))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the client script is added to the index file
+    #[inline]
+    #[must_use]
+    pub const fn injects(&self) -> bool {
+        self.conf.inject
+    }
+
+    /// `html` with the client script added before `</body>`, or at the end if there is none.
+    #[must_use]
+    pub fn inject(&self, html: &[u8]) -> Vec<u8> {
+        let at = html.windows(7).rposition(|tag| tag.eq_ignore_ascii_case(b"</body>")).unwrap_or(html.len());
+        let mut injected = Vec::with_capacity(html.len() + self.tag.len());
+        injected.extend_from_slice(&html[..at]);
+        injected.extend_from_slice(self.tag.as_bytes());
+        injected.extend_from_slice(&html[at..]);
+        injected
+    }
+
+    /// Tell every open page that the files served at `paths` changed. If they are all service workers the pages only
+    /// update their registrations, otherwise they reload.
+    pub fn notify(&self, paths: &[String]) {
+        let workers = !paths.is_empty() && paths.iter()
+            .all(|path| self.conf.service_workers.iter().any(|glob| glob.matches(path)));
+        let event = if workers { "sw-update" } else { "reload" };
+        let data = serde_json::to_string(paths).unwrap(/* infallible, strings always serialize */);
+
+        match self.events.send(format!("event: {event}\ndata: {data}\n\n").into_bytes().into()) {
+            Ok(pages) => info!("Sent `{event}` to {pages} pages for {data}"),
+            Err(_) => debug!("No pages are open to {event}")
+        }
+    }
+}
+
+/// The next chunk of the event stream, a comment if nothing happened for a while. `None` once no more events will
+/// be sent.
+pub async fn next(events: &mut Receiver) -> Option<Arc<[u8]>> {
+    match tokio::time::timeout(HEARTBEAT, events.recv()).await {
+        Ok(Ok(event)) => Some(event),
+        // whatever was missed, reloading catches up with it
+        Ok(Err(RecvError::Lagged(_))) => Some(Arc::from(&b"event: reload\ndata: []\n\n"[..])),
+        Ok(Err(RecvError::Closed)) => None,
+        Err(_) => Some(Arc::from(&b":\n\n"[..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies() {
+        let conf: &'static LiveReloadConf = Box::leak(Box::default());
+        let live: &'static LiveReload = Box::leak(Box::new(LiveReload::new(conf)));
+        let mut events = live.events.subscribe();
+        let mut next = || String::from_utf8(events.try_recv().unwrap().to_vec()).unwrap();
+
+        live.notify(&["/app/sw.js".to_owned()]);
+        assert_eq!(next(), "event: sw-update\ndata: [\"/app/sw.js\"]\n\n");
+        live.notify(&["/sw.js".to_owned(), "/main.css".to_owned()]);
+        assert!(next().starts_with("event: reload\n"));
+        live.notify(&[]);
+        assert!(next().starts_with("event: reload\n"));
+
+        assert_eq!(
+            live.inject(b"<html><body><p>hi</p></BODY></html>"),
+            b"<html><body><p>hi</p><script src=\"/__live-reload.js\" defer></script></BODY></html>"
+        );
+        assert!(live.inject(b"<p>hi</p>").ends_with(b"defer></script>"));
+
+        let parse = |raw: &'static str| Request::parse(raw.as_bytes()).unwrap();
+        let res = live.respond(&parse("GET /__live-reload HTTP/1.1\r\n\r\n")).unwrap();
+        assert!(matches!(res.body, Body::Events(_)));
+        let res = live.respond(&parse("GET /__live-reload.js?v=1 HTTP/1.1\r\n\r\n")).unwrap();
+        assert!(String::from_utf8_lossy(res.body.as_bytes()).contains("new EventSource(\"/__live-reload\")"));
+        assert!(live.respond(&parse("GET /__live-reload/ HTTP/1.1\r\n\r\n")).is_none());
+    }
+}
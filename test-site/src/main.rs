@@ -1,82 +1,53 @@
 use tokio::net::{TcpListener, TcpStream};
+use std::future::Future;
 use std::net::SocketAddr;
+use std::collections::BTreeMap;
 use std::path::Path;
-#[cfg(feature = "reload")]
 use std::path::PathBuf;
-use std::io::{self, IoSlice};
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
+use std::io;
+use std::sync::Arc;
 use tracing::{instrument, trace, debug, info, warn, Level};
 
-#[cfg(feature = "reload")]
-use std::time::SystemTime;
-#[cfg(feature = "reload")]
-use tokio::fs;
 
+mod conf;
+use conf::{ServerConf, PathsConf, FallbackConf, MountConf, BadCacheConf};
 mod parse;
-use parse::{PathIter, get_req_path};
+use parse::{PathIter, Request, Method, Headers};
+mod conn;
+mod response;
+use response::{Response, Status, Body};
 mod mime;
 mod path;
 mod route;
-use route::Tree;
+use route::SyncTree;
 mod lazy_file;
-use lazy_file::LazyFile;
-
-#[must_use]
-struct ServerConf {
-    port: u16,
-    index: &'static str,
-    dist: &'static str,
-    threads: usize
-}
-
-impl ServerConf {
-    #[instrument(name = "load-server-conf", err(Debug, level = Level::DEBUG))]
-    pub fn from_env() -> io::Result<Self> {
-        let port = option_env!("TEST_PORT")
-            .unwrap_or("6969")
-            .parse()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid port: {}", e)))?;
-        
-        let index = option_env!("TEST_SITE_PATH").unwrap_or(concat!(env!("CARGO_MANIFEST_DIR"), "/index.html"));
-        let dist = option_env!("TEST_DIST_PATH").unwrap_or(concat!(env!("CARGO_MANIFEST_DIR"), "/dist"));
-        let threads = option_env!("SERVER-THREADS").and_then(|raw| match raw.parse() {
-            Ok(tc) => {
-                info!("Using {tc} threads...");
-                Some(tc)
-            },
-            Err(e) => {
-                warn!("Requested thread count was not a number: {e:?}");
-                None
-            }
-        }).unwrap_or_else(|| num_cpus::get());
-
-        macro_rules! cfg_has {
-            ($meta:meta) => {{
-                #[cfg($meta)] {
-                    true
-                }
-                #[cfg(not($meta))] {
-                    false
-                }
-            }}
-        }
-
-        const RELOADS: bool = cfg_has!(feature = "reload");
-        const BAD_CACHE: bool = cfg_has!(feature = "bad-cache");
-
-        info!(
-            "Server Configuration:\
-            \n\t TEST_SITE_PATH: {index},\
-            \n\t TEST_DIST_PATH: {dist},\
-            \n\t TEST_PORT: {port},\
-            \n\t SERVER-THREADS: {threads},\
-            \n\t HOT RELOADS: {RELOADS},\
-            \n\t 404 CACHING: {BAD_CACHE}",
-        );
-        
-        Ok(Self { port, index, dist, threads })
-    }
-}
+use lazy_file::{LazyFile, Contents, Src};
+mod conditional;
+mod range;
+mod encoding;
+use encoding::Encoding;
+mod accept;
+mod listing;
+mod glob;
+mod error;
+mod rules;
+use rules::Rules;
+mod cors;
+use cors::Cors;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "http2")]
+mod http2;
+#[cfg(feature = "bad-cache")]
+mod bad_cache;
+#[cfg(feature = "bad-cache")]
+use bad_cache::BadCache;
+#[cfg(feature = "reload")]
+mod watch;
+#[cfg(feature = "reload")]
+mod live;
+#[cfg(feature = "reload")]
+use live::LiveReload;
 
 fn main() -> io::Result<()> {
     tracing_subscriber::fmt()
@@ -87,11 +58,12 @@ fn main() -> io::Result<()> {
         .with_line_number(false)
         .init();
 
-    let conf = ServerConf::from_env()?;
-    let addr = SocketAddr::from(([127, 0, 0, 1], conf.port));
+    let conf = ServerConf::load()?;
+    let addr = SocketAddr::new(conf.host, conf.port);
     
     tokio::runtime::Builder::new_multi_thread()
         .enable_io()
+        .enable_time()
         .worker_threads(conf.threads)
         .build()
         .and_then(move |rt| rt.block_on(make_serve(addr, conf)))
@@ -100,253 +72,773 @@ fn main() -> io::Result<()> {
 #[instrument(name = "server", skip(conf), level = Level::DEBUG)]
 async fn make_serve(addr: SocketAddr, conf: ServerConf) -> io::Result<()> {
     let listener = TcpListener::bind(addr).await?;
-    let index = Box::leak(Box::new(IndexFile::new(conf.index)?));
+    let conf: &'static ServerConf = Box::leak(Box::new(conf));
+    let site: &'static Site = Box::leak(Box::new(Site::new(conf)?));
+    #[cfg(feature = "reload")]
+    site.watch()?;
 
-    // SAFETY: all mutable operations on dist_handler are completely synchronous (0 interrupts) and on the main 
-    // thread. 
-    let dist_handler = core::cell::UnsafeCell::new(Box::leak(Box::new(DistHandler::new(conf.dist))));
+    #[cfg(feature = "tls")]
+    let tls: Option<&'static tokio_rustls::TlsAcceptor> = match &conf.tls {
+        Some(tls_conf) => {
+            if let Some(redirect_port) = tls_conf.redirect_port {
+                let redirect = TcpListener::bind(SocketAddr::new(conf.host, redirect_port)).await?;
+                info!("Redirecting HTTP on port {redirect_port} to HTTPS...");
+                tokio::spawn(accept_loop(redirect, move |stream| {
+                    conn::serve(stream, Handler::Redirect(conf.port), &conf.http)
+                }));
+            }
+            Some(Box::leak(Box::new(tls::acceptor(tls_conf, conf.host)?)))
+        },
+        None => None
+    };
 
     info!("Server listening...");
 
+    accept_loop(listener, move |stream| async move {
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = tls {
+            // the handshake is bounded like the rest of the request head
+            let stream = match tokio::time::timeout(conf.http.read_timeout(), acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!("TLS handshake failed: {err:?}");
+                    return Err(err);
+                },
+                Err(_) => {
+                    debug!("TLS handshake timed out");
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            };
+            #[cfg(feature = "http2")]
+            if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                return http2::serve(stream, Handler::Site(site), &conf.http).await;
+            }
+            return conn::serve(stream, Handler::Site(site), &conf.http).await;
+        }
+
+        conn::serve(stream, Handler::Site(site), &conf.http).await
+    }).await
+}
+
+/// Accept connections on `listener` forever, spawning `serve` for each of them.
+async fn accept_loop<F, Fut>(listener: TcpListener, serve: F) -> io::Result<()>
+    where F: Fn(TcpStream) -> Fut, Fut: Future<Output = io::Result<()>> + Send + 'static
+{
     loop {
-        let _ = match listener.accept().await {
+        match listener.accept().await {
             Ok((stream, remote_addr)) => {
                 debug!("Connection {remote_addr} accepted");
-                conn_handler(stream, index, unsafe { &mut *dist_handler.get() }).await
+                if let Err(e) = stream.set_nodelay(true) {
+                    warn!("Failed to set TCP_NODELAY: {e:?}");
+                }
+                tokio::spawn(serve(stream));
             },
             Err(e) => {
                 warn!("Error accepting connection: {e:?}");
-                continue
             }
+        }
+    }
+}
+
+/// Unwrap `$fallible`, otherwise return the status to report it with (see [`error::status`]) from the enclosing
+/// function.
+macro_rules! or_error {
+    ($fallible:expr, |$ret:ident| $ok:expr) => {
+        match $fallible {
+            Ok($ret) => $ok,
+            Err(__err) => {
+                let __status = error::status(&__err);
+                debug!("Responding {} to: {__err:?}", __status.code());
+                return Err(__status);
+            }
+        }
+    }
+}
+
+/// The methods we respond to, everything is read-only.
+const ALLOW: &str = "GET, HEAD, OPTIONS";
+
+/// What a connection's requests are answered by.
+#[derive(Copy, Clone)]
+pub enum Handler {
+    Site(&'static Site),
+    /// Redirect every request to HTTPS on the given port
+    #[cfg(feature = "tls")]
+    Redirect(u16)
+}
+
+impl Handler {
+    #[inline]
+    pub async fn respond(self, req: &Request<'_>) -> Response {
+        match self {
+            Self::Site(site) => site.respond(req).await,
+            #[cfg(feature = "tls")]
+            Self::Redirect(port) => tls::redirect(req, port)
+        }
+    }
+}
+
+/// Everything required to respond to a request, shared between all connections.
+pub struct Site {
+    /// Prefixed mounts served whatever the `Host`
+    default: Mounts,
+    /// Mounts only served for requests to a specific host, consulted before the default ones
+    hosts: Vec<(&'static str, Mounts)>,
+    cors: Option<Cors>,
+    /// Every mount, whichever host it belongs to, starting with the root which serves whatever no other mount does
+    mounts: Vec<Arc<Mount>>,
+    #[cfg(feature = "reload")]
+    live: Option<&'static LiveReload>
+}
+
+impl Site {
+    pub fn new(conf: &'static ServerConf) -> io::Result<Self> {
+        // shared by every mount
+        let rules: &'static Rules = Box::leak(Box::new(Rules::new(&conf.headers)));
+        #[cfg(feature = "reload")]
+        let live: Option<&'static LiveReload> = conf.live_reload.as_ref()
+            .map(|live| &*Box::leak(Box::new(LiveReload::new(live))));
+        let root = Arc::new(Mount {
+            prefix: "",
+            index: Some(Variants::new(PathBuf::from(conf.index), None)?),
+            dist: DistHandler::new(conf.dist, &conf.paths, &conf.bad_cache),
+            fallback: conf.fallback.as_ref(),
+            errors: &conf.errors,
+            rules,
+            headers: Vec::new(),
+            #[cfg(feature = "reload")]
+            inject: live.filter(|live| live.injects())
+        });
+        let mut site = Self {
+            default: Mounts::new(),
+            hosts: Vec::new(),
+            cors: conf.cors.as_ref().map(Cors::new),
+            mounts: vec![root],
+            #[cfg(feature = "reload")]
+            live
         };
+
+        for mount_conf in &conf.mounts {
+            let mount = Mount::new(
+                mount_conf, conf, rules,
+                #[cfg(feature = "reload")]
+                live
+            )?;
+            let mounts = match mount_conf.host.as_deref() {
+                None => &mut site.default,
+                Some(host) => match site.hosts.iter().position(|(name, _)| name.eq_ignore_ascii_case(host)) {
+                    Some(i) => &mut site.hosts[i].1,
+                    None => {
+                        site.hosts.push((host, Mounts::new()));
+                        &mut site.hosts.last_mut().unwrap(/* infallible, we just pushed */).1
+                    }
+                }
+            };
+            site.mounts.push(mounts.insert(mount));
+        }
+
+        Ok(site)
+    }
+
+    /// Watch the dist directories and index files for changes, reloading files and invalidating routes as they change.
+    #[cfg(feature = "reload")]
+    fn watch(&'static self) -> io::Result<()> {
+        let mut roots: Vec<(PathBuf, bool)> = Vec::new();
+        for mount in &self.mounts {
+            let dist = PathBuf::from(mount.dist.dist);
+            if !roots.contains(&(dist.clone(), true)) {
+                roots.push((dist, true));
+            }
+        }
+        let indexes = self.mounts.iter().filter_map(|mount| mount.index.as_ref()).map(|index| &index.path);
+        for index in indexes {
+            let dir = index.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            let watched = roots.iter()
+                .any(|(root, recursive)| if *recursive { dir.starts_with(root) } else { dir == root });
+            if !watched {
+                roots.push((dir.to_path_buf(), false));
+            }
+        }
+
+        watch::spawn(roots, move |changes| {
+            for mount in &self.mounts {
+                mount.dist.invalidate(changes);
+            }
+            if let Some(live) = self.live {
+                let paths = self.mounts.iter().flat_map(|mount| mount.changed(changes)).collect::<Vec<_>>();
+                if changes.rescan || !paths.is_empty() {
+                    live.notify(&paths);
+                }
+            }
+        })
+    }
+
+    #[instrument(
+        name = "request",
+        skip_all,
+        fields(method = %req.method, target = %String::from_utf8_lossy(req.target)),
+        level = Level::DEBUG
+    )]
+    pub async fn respond(&self, req: &Request<'_>) -> Response {
+        trace!("Responding to the request ({} headers)", req.headers.len());
+        if let Some(query) = req.query() {
+            trace!("Ignoring the query `{}`", String::from_utf8_lossy(query.raw()));
+        }
+
+        let Some(cors) = &self.cors else { return self.route(req).await };
+        if let Some(res) = cors.preflight(req) {
+            trace!("Answering the CORS preflight request");
+            return res;
+        }
+        cors.apply(req, self.route(req).await)
+    }
+
+    /// The mount serving `dist` and `index` at `/`
+    #[inline]
+    fn root(&self) -> &Arc<Mount> {
+        &self.mounts[0]
+    }
+
+    /// Respond to `req` from the mount its host and path resolve to.
+    async fn route(&self, req: &Request<'_>) -> Response {
+        #[cfg(feature = "reload")]
+        if let Some(res) = self.live.and_then(|live| live.respond(req)) {
+            trace!("Answering from live reload");
+            return res;
+        }
+        if req.method == Method::Options {
+            return Response::new(Status::NO_CONTENT, Body::Empty).header("Allow", ALLOW);
+        }
+
+        // `PathIter` is not `Send`, so only what remains of the path is held across the response
+        let (mount, rest) = {
+            let mut path = req.path();
+            let host = req.hostname()
+                .and_then(|hostname| self.hosts.iter().find(|(name, _)| name.eq_ignore_ascii_case(hostname)));
+            let mount = host.and_then(|(_, mounts)| mounts.resolve(&mut path))
+                .or_else(|| self.default.resolve(&mut path))
+                .unwrap_or_else(|| self.root().clone());
+            (mount, path.rest())
+        };
+        trace!("Routed to the mount at `/{}`", mount.prefix);
+
+        let res = match req.method {
+            // the body of `HEAD` responses is omitted when written
+            Method::Get | Method::Head => match mount.respond(req, rest).await {
+                Ok(res) => res,
+                Err(status) => mount.error(status, req).await
+            },
+            _ => mount.error(Status::METHOD_NOT_ALLOWED, req).await.header("Allow", ALLOW)
+        };
+        // the most specific, so they replace any header of the same name set by the rules or ourselves
+        mount.headers.iter().fold(res, |res, (name, value)| res.set_header(name, value.clone()))
     }
 }
 
-macro_rules! serve_file {
-    (@mime $stream:ident $(,)?) => {
-        $stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n").await
-    };
-    (@mime $stream:ident, $mime:ident) => {
-        $stream.write_vectored(&[
-            IoSlice::new(b"HTTP/1.1 200 OK\r\nContent-Type: "),
-            IoSlice::new($mime.as_bytes()),
-            IoSlice::new(b"\r\n\r\n")
-        ]).await
-    };
-    ($stream:ident, $file:expr $(, $mime:ident)?) => {
-        match serve_file!(@mime $stream $(, $mime)?) {
-            Ok(_) => match $file.write(&mut $stream).await {
-                Ok(_a) => {
-                    debug!("Finished serving request, flushing...");
-                    $stream.flush().await
-                },
-                Err(err) => Err(err)
+/// The mounts of a host, resolved by the longest prefix of the request path. Only the mounts of a specific host may
+/// have a root, the default one being that of the [`Site`].
+struct Mounts {
+    prefixed: SyncTree<Mount>,
+    root: Option<Arc<Mount>>
+}
+
+impl Mounts {
+    #[inline]
+    fn new() -> Self {
+        Self { prefixed: SyncTree::new(), root: None }
+    }
+
+    fn insert(&mut self, mount: Mount) -> Arc<Mount> {
+        let mount = Arc::new(mount);
+        if mount.prefix.is_empty() {
+            self.root = Some(mount.clone());
+        } else {
+            self.prefixed.replace(PathIter::new(mount.prefix.as_bytes()), mount.clone());
+        }
+        mount
+    }
+
+    /// The mount `path` falls under, advancing `path` to what remains past its prefix.
+    #[inline]
+    fn resolve(&self, path: &mut PathIter) -> Option<Arc<Mount>> {
+        self.prefixed.longest_match(path).or_else(|| self.root.clone())
+    }
+}
+
+/// A directory served under a URL prefix, with its own index, fallback and headers.
+struct Mount {
+    /// The prefix without its leading and trailing slashes, empty for the root
+    prefix: &'static str,
+    index: Option<Variants>,
+    dist: DistHandler,
+    fallback: Option<&'static FallbackConf>,
+    /// Error pages by status, within `dist`
+    errors: &'static BTreeMap<u16, String>,
+    /// Headers attached to files by their path and MIME type
+    rules: &'static Rules,
+    headers: Vec<(&'static str, String)>,
+    /// Adds the live reload client to the index, if it is to be injected
+    #[cfg(feature = "reload")]
+    inject: Option<&'static LiveReload>
+}
+
+impl Mount {
+    fn new(
+        conf: &'static MountConf, site: &'static ServerConf, rules: &'static Rules,
+        #[cfg(feature = "reload")]
+        live: Option<&'static LiveReload>
+    ) -> io::Result<Self> {
+        let paths = &site.paths;
+        let dist: &'static str = Box::leak(conf.dist.to_string_lossy().into_owned().into_boxed_str());
+        let index = match &conf.index {
+            Some(index) => Some(index.clone()),
+            None => paths.index_files.iter().map(|name| conf.dist.join(name)).find(|index| index.is_file())
+        };
+
+        Ok(Self {
+            prefix: conf.trimmed_prefix(),
+            index: index.map(|index| Variants::new(index, None)).transpose()?,
+            dist: DistHandler::new(dist, paths, &site.bad_cache),
+            fallback: conf.fallback.as_ref(),
+            errors: &site.errors,
+            rules,
+            headers: conf.headers.iter()
+                .map(|(name, value)| (&*Box::leak(name.clone().into_boxed_str()), value.clone()))
+                .collect(),
+            #[cfg(feature = "reload")]
+            inject: live.filter(|live| live.injects())
+        })
+    }
+
+    /// Respond to `req`, where `rest` is what remains of its path past our prefix, or with the status of the error
+    /// which prevented us.
+    async fn respond(&self, req: &Request<'_>, rest: &[u8]) -> Result<Response, Status> {
+        if !self.prefix.is_empty() && rest.is_empty() {
+            trace!("Mount requested without its trailing slash, redirecting...");
+            return Ok(add_slash(req));
+        }
+
+        // the remainder of prefixed mounts starts with the slash which followed the prefix
+        let routed = match self.dist.try_route(PathIter::new(rest.strip_prefix(b"/").unwrap_or(rest))) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound && self.falls_back(req) => {
+                trace!("Not found, falling back to the index file: {err:?}");
+                return or_error!(self.serve_index(req).await, |res| Ok(res));
+            },
+            routed => or_error!(routed, |r| r)
+        };
+
+        match routed.as_deref() {
+            Some(DistEntry::File(d_re)) => {
+                trace!("Routed to dist directory...");
+                let served = serve_dist(d_re, req).await;
+                if matches!(&served, Err(err) if err.kind() == io::ErrorKind::NotFound) {
+                    // removed or renamed since we routed to it
+                    self.dist.forget(PathIter::new(rest.strip_prefix(b"/").unwrap_or(rest)));
+                }
+                or_error!(served, |res| Ok(self.rules.apply(req, d_re.mime, res)))
+            },
+            Some(DistEntry::Directory(dir)) => {
+                trace!("Routed to the directory {} without its trailing slash, redirecting...", dir.display());
+                Ok(add_slash(req))
+            },
+            Some(DistEntry::Listing(dir)) => {
+                trace!("Routed to a directory without an index file, listing it...");
+                // listings are read afresh, so the directory is confined afresh too
+                let dir = or_error!(path::resolve(self.dist.confine.as_ref(), dir), |dir| dir);
+                or_error!(listing::respond(&dir, req).await, |res| Ok(res))
+            },
+            None => {
+                trace!("Routed to the index file...");
+                or_error!(self.serve_index(req).await, |res| Ok(res))
+            }
+        }
+    }
+
+    /// Respond with the error `status`, as JSON if the client prefers it, otherwise with our page for it if there is
+    /// one. The page is routed like any other file, so it is cached and reloaded along with the rest of `dist`.
+    async fn error(&self, status: Status, req: &Request<'_>) -> Response {
+        if error::wants_json(req) {
+            return error::json(status);
+        }
+        let Some(page) = self.errors.get(&status.code()) else {
+            return error::plain(status);
+        };
+
+        let loaded = match self.dist.try_route(PathIter::new(page.as_bytes())) {
+            Ok(Some(entry)) => match &*entry {
+                DistEntry::File(d_re) => d_re.file.negotiate(&req.headers, "text/html").await,
+                _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "The error page is a directory"))
             },
+            Ok(None) => Err(io::Error::new(io::ErrorKind::InvalidInput, "The error page is the index")),
             Err(err) => Err(err)
+        };
+        match loaded {
+            Ok((src, encoding)) => error::page(status, &src, encoding),
+            Err(err) => {
+                debug!("Could not load the {} page `{page}`: {err:?}", status.code());
+                error::plain(status)
+            }
         }
-    };
-}
+    }
 
-macro_rules! or_404 {
-    ($fallible:expr, $stream:ident, || $or:expr, |$ret:ident| $ok:expr) => {
-        match $fallible {
-            Ok($ret) => $ok,
-            Err(__err) => {
-                tokio::spawn(write_status($stream, "404", "Not Found")).await??;
-                debug!("Served 404 error: {__err:?}");
-                $or
+    async fn serve_index(&self, req: &Request<'_>) -> io::Result<Response> {
+        let Some(index) = &self.index else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "The mount has no index file"));
+        };
+
+        #[cfg(feature = "reload")]
+        if let Some(live) = self.inject {
+            // injected into every response, so neither precompressed siblings nor the compressed copy apply
+            let src = index.file.load().await?;
+            let injected = Contents::new(live.inject(src.bytes()), src.modified());
+            let res = serve_file(&injected, Encoding::Identity, "text/html", req);
+            return Ok(self.rules.apply(req, "text/html", res));
+        }
+        serve_index(index, req).await.map(|res| self.rules.apply(req, "text/html", res))
+    }
+
+    /// The request paths of the files among `changes`, the index being served at our prefix.
+    #[cfg(feature = "reload")]
+    fn changed(&self, changes: &watch::Changes) -> Vec<String> {
+        let prefix = if self.prefix.is_empty() { "/".to_owned() } else { format!("/{}/", self.prefix) };
+        let dist = watch::absolute(Path::new(self.dist.dist));
+        let index = self.index.as_ref().map(|index| watch::absolute(&index.path));
+
+        changes.paths.keys().filter_map(|path| {
+            if index.as_deref() == Some(path.as_path()) {
+                return Some(prefix.clone());
             }
+            let rel = path.strip_prefix(&dist).ok()?;
+            // precompressed siblings stand in for the file they were compressed from
+            let rel = match rel.extension().and_then(|ext| ext.to_str()) {
+                Some("br" | "gz") => rel.with_extension(""),
+                _ => rel.to_path_buf()
+            };
+            let segments = rel.components().map(|segment| segment.as_os_str().to_string_lossy()).collect::<Vec<_>>();
+            Some(format!("{prefix}{}", segments.join("/")))
+        }).collect()
+    }
+
+    /// Whether a request for a path which does not exist should be answered with the index, as single page apps route
+    /// on the client. Only navigations fall back, so that missing assets and API calls are still reported as missing.
+    fn falls_back(&self, req: &Request) -> bool {
+        let Some(fallback) = self.fallback else { return false };
+
+        // decoded first, so that `app%2Ejs` is seen to have an extension
+        let path = req.origin_path();
+        let path = parse::percent_decode(path).unwrap_or(std::borrow::Cow::Borrowed(path));
+        let path = String::from_utf8_lossy(&path);
+        let last = path.rsplit('/').next().unwrap_or_default();
+        if last.contains('.') || !accept::explicitly(&req.headers, "text/html") {
+            return false;
         }
+
+        glob::any(&fallback.include, &path) && !glob::any(&fallback.exclude, &path)
     }
 }
 
-#[inline]
-#[instrument(
-    name = "request",
-    skip(index, d_h),
-    err(Debug, level = Level::DEBUG),
-    level = Level::DEBUG
-)]
-async fn conn_handler<P>(
-    mut stream: TcpStream, 
-    index: &'static IndexFile<P>, d_h: &'static mut DistHandler
-) -> io::Result<()> 
-    where P: AsRef<Path> + core::fmt::Debug + Send + Sync
-{
-    stream.set_nodelay(true)?;
-    let buf = Buf::<{ 2usize.pow(10) }>::read(&mut stream).await?;
-    trace!("Successfully read the request");
-
-    if let Some(d_re) = or_404!(d_h.try_route(buf.get()), stream, || return Ok(()), |r| r) {
-        trace!("Routed to dist directory...");
-        tokio::spawn(serve_dist(stream, d_re));
+/// Respond with `src` (encoded with `encoding`) and its validators, or `304` if the client's copy is still fresh.
+/// `GET` requests may ask for parts of the file with `Range`.
+///
+/// Responses are marked `no-cache`, browsers may store them but must revalidate each time, which with validators costs
+/// an empty `304` while making sure edits to the dist directory are never hidden by a stale cache.
+fn serve_file(src: &Contents, encoding: Encoding, mime: &'static str, req: &Request) -> Response {
+    let res = if conditional::not_modified(&req.headers, src) {
+        trace!("Client's copy is still fresh, responding 304");
+        Response::new(Status::NOT_MODIFIED, Body::Empty)
     } else {
-        trace!("Routed to the index file...");
-        tokio::spawn(serve_index(stream, index));
+        // range requests are only defined for `GET`, `HEAD` must describe the full file
+        let ranges = match req.method {
+            Method::Get => range::requested(&req.headers, src),
+            _ => range::Ranges::Full
+        };
+        range::response(ranges, src, mime)
+    }
+        .header("Accept-Ranges", "bytes")
+        .header("Vary", "Accept-Encoding")
+        .header("ETag", src.etag().to_owned())
+        .header("Cache-Control", "no-cache");
+
+    let res = match encoding {
+        Encoding::Identity => res,
+        encoding => res.header("Content-Encoding", encoding.as_str())
     };
+    match src.last_modified() {
+        Some(last_modified) => res.header("Last-Modified", last_modified.to_owned()),
+        None => res
+    }
+}
 
-    Ok(())
+async fn serve_index(index: &Variants, req: &Request<'_>) -> io::Result<Response> {
+    let (src, encoding) = index.negotiate(&req.headers, "text/html").await?;
+    Ok(serve_file(&src, encoding, "text/html", req))
 }
 
-async fn serve_index<P>(mut stream: TcpStream, index: &'static IndexFile<P>) -> io::Result<()>
-    where P: AsRef<Path> + core::fmt::Debug + Sync + Send
-{
-    #[cfg(feature = "reload")] {
-        index.reload.maybe(&index.file).await?;
+/// Redirect to the directory requested without its trailing slash, so that relative URLs within its index resolve
+/// against it.
+fn add_slash(req: &Request) -> Response {
+    // empty segments are routed past, though `//dir/` would be taken as a redirect to the host `dir`
+    let path = String::from_utf8_lossy(req.origin_path());
+    let mut location = format!("/{}", path.trim_start_matches('/'));
+    location.push('/');
+    if let Some(query) = req.query() {
+        location.push('?');
+        location.push_str(&String::from_utf8_lossy(query.raw()));
     }
-    serve_file!(stream, &index.file)
+    Response::status(Status::MOVED_PERMANENTLY).header("Location", location)
 }
 
 #[inline(always)]
-async fn serve_dist(mut stream: TcpStream, d_re: &'static DistReload) -> io::Result<()> {
-    let (file, mime) = (&d_re.file, d_re.mime);
-    #[cfg(feature = "reload")] {
-        d_re.reload.maybe(file).await?;
-    }
-    serve_file!(stream, file, mime)
+async fn serve_dist(d_re: &DistReload, req: &Request<'_>) -> io::Result<Response> {
+    let (src, encoding) = d_re.file.negotiate(&req.headers, d_re.mime).await?;
+    Ok(serve_file(&src, encoding, d_re.mime, req))
 }
 
-#[must_use]
-struct Buf<const C: usize> {
-    buf: [u8; C],
-    len: usize
+/// A file which, with the `reload` feature, is refreshed by the watcher whenever it changes on disk.
+#[repr(transparent)]
+struct TrackedFile {
+    #[cfg(feature = "reload")]
+    file: Arc<LazyFile>,
+    #[cfg(not(feature = "reload"))]
+    file: LazyFile
 }
 
-impl<const C: usize> Buf<C> {
+impl TrackedFile {
+    /// Open the file requested at `path` from `resolved`, where it must be found exactly if `confined`.
     #[inline]
-    pub async fn read(stream: &mut TcpStream) -> io::Result<Self> {
-        let mut buf = [0; C];
-        stream.read(&mut buf).await.map(move |len| Self { buf, len })
+    pub fn new(path: &Path, resolved: &Path, confined: bool) -> io::Result<Self> {
+        trace!("Opening {} from {}", path.display(), resolved.display());
+        let file = LazyFile::new(resolved, confined)?;
+        #[cfg(feature = "reload")]
+        let file = {
+            let file = Arc::new(file);
+            // changes are reported at either, depending on which the watcher followed
+            watch::register(path, &file);
+            if resolved != path {
+                watch::register(resolved, &file);
+            }
+            file
+        };
+        Ok(Self { file })
     }
 
     #[inline]
-    pub fn get<'a>(&'a self) -> &'a [u8] {
-        &self.buf[..self.len]
+    pub async fn load(&self) -> io::Result<Src> {
+        self.file.load().await
     }
 }
 
-#[cfg(feature = "reload")]
-struct IndexFile<P> {
-    file: LazyFile,
-    reload: Reload<P>
+/// A file along with its precompressed siblings (`bundle.js.br` and `bundle.js.gz` for `bundle.js`), if present.
+struct Variants {
+    /// Where `file` was opened from
+    #[cfg_attr(not(feature = "reload"), allow(dead_code))]
+    path: PathBuf,
+    file: TrackedFile,
+    br: Option<TrackedFile>,
+    gzip: Option<TrackedFile>
 }
 
-#[cfg(not(feature = "reload"))]
-#[repr(transparent)]
-struct IndexFile<P> {
-    file: LazyFile,
-    _p: core::marker::PhantomData<P>
-}
+impl Variants {
+    /// Open `path` and its siblings, which when `confine` is set must each resolve within the dist directory.
+    pub fn new(path: PathBuf, confine: Option<&path::Confine>) -> io::Result<Self> {
+        let open = |path: &Path| TrackedFile::new(path, &path::resolve(confine, path)?, confine.is_some());
 
-impl<P: AsRef<Path> + core::fmt::Debug> IndexFile<P> {
-    #[inline]
-    pub fn new(path: P) -> io::Result<Self> {
-        LazyFile::new(path.as_ref()).map(|file| Self {
-            file,
-            #[cfg(feature = "reload")]
-            reload: Reload::new(path),
-            #[cfg(not(feature = "reload"))]
-            _p: core::marker::PhantomData
+        let sibling = |encoding: Encoding| encoding.extension().map(|ext| {
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(".");
+            sibling.push(ext);
+            PathBuf::from(sibling)
+        });
+        let (br, gzip) = (sibling(Encoding::Brotli), sibling(Encoding::Gzip));
+
+        Ok(Self {
+            file: open(&path)?,
+            br: br.and_then(|path| open(&path).ok()),
+            gzip: gzip.and_then(|path| open(&path).ok()),
+            path
         })
     }
+
+    #[inline]
+    const fn precompressed(&self, encoding: Encoding) -> Option<&TrackedFile> {
+        match encoding {
+            Encoding::Brotli => self.br.as_ref(),
+            Encoding::Gzip => self.gzip.as_ref(),
+            Encoding::Identity => None
+        }
+    }
+
+    /// Pick the representation most preferred by the client. Precompressed siblings take precedence over compressing
+    /// the file on the fly, which is only done for compressible MIME types.
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    pub async fn negotiate(&self, headers: &Headers<'_>, mime: &str) -> io::Result<(Src, Encoding)> {
+        for encoding in encoding::accepted(headers).iter() {
+            if encoding == Encoding::Identity {
+                break;
+            }
+
+            if let Some(sibling) = self.precompressed(encoding) {
+                match sibling.load().await {
+                    Ok(src) => return Ok((src, encoding)),
+                    Err(err) => debug!("Failed to load the precompressed sibling: {err:?}")
+                }
+            }
+
+            #[cfg(feature = "compression")] {
+                let src = self.file.load().await?;
+                if encoding::compressible(mime, src.bytes().len()) {
+                    if let Some(encoded) = src.encoded(encoding).await {
+                        return Ok((encoded, encoding));
+                    }
+                }
+            }
+        }
+
+        self.file.load().await.map(|src| (src, Encoding::Identity))
+    }
 }
 
 struct DistHandler {
-    seen: Tree<'static, DistReload>,
+    seen: SyncTree<DistEntry>,
     dist: &'static str,
+    paths: &'static PathsConf,
+    confine: Option<path::Confine>,
     #[cfg(feature = "bad-cache")]
-    bc: lru::LruCache<std::path::PathBuf, ()>
+    bc: BadCache
 }
 
 impl DistHandler {
     #[must_use]
     #[inline]
-    pub fn new(dist: &'static str) -> Self {
+    #[cfg_attr(not(feature = "bad-cache"), allow(unused_variables))]
+    pub fn new(dist: &'static str, paths: &'static PathsConf, bad_cache: &BadCacheConf) -> Self {
         Self {
-            seen: Tree::new_static(),
+            seen: SyncTree::new(),
             dist,
+            paths,
+            confine: path::Confine::new(dist, paths),
             #[cfg(feature = "bad-cache")]
-            bc: lru::LruCache::new(core::num::NonZeroUsize::new(8).unwrap())
+            bc: BadCache::new(bad_cache)
         }
     }
 
     #[inline(always)]
-    pub fn try_route(&'static mut self, raw: &[u8]) -> io::Result<Option<&'static DistReload>>  {
+    pub fn try_route(&self, path: PathIter) -> io::Result<Option<Arc<DistEntry>>>  {
         let e = std::time::Instant::now();
 
         #[cfg(feature = "bad-cache")]
-        let bc = &mut self.bc;
-
-        let dist = self.dist;
-        match get_req_path(raw) { 
-            Ok(path) if path.next_known_terminal() => Ok(None),
-            Ok(path) => match self.seen.get_or_try_create(
-                path,
-                move |parsed| DistReload::new(
-                    dist, path, parsed,
-                    #[cfg(feature = "bad-cache")]
-                    bc
-                )
-            ) {
-                Ok(d_re) => {
-                    info!("Total route time: {:?}", e.elapsed());
-                    Ok(Some(d_re))
-                },
-                Err(err) => {
-                    info!("error time: {:?}", e.elapsed());
-                    Err(err)
-                }
+        let bc = &self.bc;
+
+        let (dist, paths, confine) = (self.dist, self.paths, self.confine.as_ref());
+        if path.next_known_terminal() {
+            return Ok(None);
+        }
+        // however the path is spelled, it shares a single route
+        let normal = path::normalize(path.rest());
+        let path = PathIter::new(&normal);
+
+        match self.seen.get_or_try_create(
+            path,
+            move |_| DistEntry::new(
+                dist, paths, confine, path,
+                #[cfg(feature = "bad-cache")]
+                bc
+            )
+        ) {
+            Ok(d_re) => {
+                info!("Total route time: {:?}", e.elapsed());
+                Ok(Some(d_re))
             },
-            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+            Err(err) => {
+                info!("error time: {:?}", e.elapsed());
+                Err(err)
+            }
+        }
+    }
+
+    /// Stop routing `path`, whose file was found missing when serving it, so that it is looked up afresh next time.
+    #[inline]
+    pub fn forget(&self, path: PathIter) {
+        if self.seen.remove(PathIter::new(&path::normalize(path.rest()))).is_some() {
+            debug!("Forgot the route to a missing file in {}", self.dist);
+        }
+    }
+}
+
+#[cfg(feature = "reload")]
+impl DistHandler {
+    /// Drop the routes affected by `changes`, along with the paths in the `bad-cache` which may now exist.
+    pub fn invalidate(&self, changes: &watch::Changes) {
+        let root = watch::absolute(Path::new(self.dist));
+        let relative = changes.structural().filter_map(|path| path.strip_prefix(&root).ok()).collect::<Vec<_>>();
+        if relative.is_empty() && !changes.rescan {
+            return;
+        }
+
+        // the changed paths as we would have built them when routing
+        let changed = relative.iter().map(|rel| Path::new(self.dist).join(rel)).collect::<Vec<_>>();
+        if changes.rescan {
+            self.seen.clear();
+            debug!("Dropped every route in {}", self.dist);
+        } else {
+            let dropped = self.seen.retain(|entry| !changed.iter().any(|path| entry.depends_on(path)));
+            debug!("Dropped {dropped} routes in {}", self.dist);
+        }
+
+        #[cfg(feature = "bad-cache")] {
+            let forgotten = self.bc.invalidate(&relative, changes.rescan);
+            debug!("Forgot {forgotten} missing paths in {}", self.dist);
         }
     }
 }
 
 struct DistReload {
-    #[cfg(feature = "reload")]
-    reload: Reload<PathBuf>,
-    file: LazyFile,
+    file: Variants,
     mime: &'static str,
 }
 
 impl DistReload {
-    #[inline(always)]
-    #[cfg(all(unix, feature = "bad-cache"))]
-    pub fn check_cache(
-        mut parsed: PathIter, 
-        bc: &mut lru::LruCache<std::path::PathBuf, ()>
-    ) -> io::Result<()> {
-        trace!("Checking cache for the path (unix)");
-        let now = std::time::Instant::now();
-	
-        // ensure iterator is exhausted
-        while parsed.next().is_some() {}
-
-        trace!("Time took for exhausting path iterator: {:?}", now.elapsed());
-
-        // get the entirety of what we have parsed
-        let parsed_slice = parsed.get_parsed();
-
-        // now for the unsafe, platform specific things... 
-        let path: &Path = unsafe {
-            // Path carries the same representation as OsStr. 
-            // OsStr carries the same representation as sys::Slice.
-            // sys::Slice carries the same representation as [u8]
-            // 
-            // This is of course, only for unix based operating systems, as less cultured operating systems like 
-            // windows use a utf16 representation for paths. Technically, this possibly is safe on windows, though,
-            // I'm not interested in chancing it, and odds are windows users do not care for this level of performance.
-            core::mem::transmute(parsed_slice)
-        };
+    /// Open the file at `p_buf`, remembering it in the `bad-cache` if it does not exist.
+    #[cfg_attr(not(feature = "bad-cache"), allow(unused_variables))]
+    fn open(
+        dist: &'static str, p_buf: PathBuf, confine: Option<&path::Confine>,
+        #[cfg(feature = "bad-cache")]
+        bc: &BadCache
+    ) -> io::Result<Self> {
+        match Variants::new(p_buf.clone(), confine) {
+            Ok(file) => {
+                let mime = p_buf.as_path().extension()
+                    .and_then(|ext| ext.to_str())
+                    .and_then(|ext| mime::from_ext(ext))
+                    .unwrap_or("application/octet-stream");
+                Ok(Self { file, mime })
+            },
+            // forbidden paths exist, so they are kept out of the `bad-cache` to be reported as forbidden each time
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Err(err),
+            Err(err) => {
+                #[cfg(feature = "bad-cache")] {
+                    debug!("Path did not exist, adding to the `bad-cache`");
 
-        if bc.get(path).is_some() {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "Path does not exist"))
-        } else {
-            Ok(())
+                    bc.insert(p_buf.strip_prefix(dist).unwrap(/* infallible */).to_path_buf());
+                }
+                Err(err)
+            }
         }
     }
- 
+}
+
+/// What a path within the dist directory routes to.
+// always behind an `Arc` in the tree, so the size of the file is of no concern
+#[allow(clippy::large_enum_variant)]
+enum DistEntry {
+    File(DistReload),
+    /// A directory requested without its trailing slash
+    Directory(PathBuf),
+    /// A directory without an index file, when listings are enabled. Confined again whenever it is listed.
+    Listing(PathBuf)
+}
 
+impl DistEntry {
     #[instrument(
         name = "load-file",
         skip_all, fields(dist = dist),
@@ -354,117 +846,385 @@ impl DistReload {
         level = Level::DEBUG
     )]
     pub fn new(
-        dist: &'static str, path: PathIter, parsed: PathIter,
+        dist: &'static str, paths: &PathsConf, confine: Option<&path::Confine>, path: PathIter,
         #[cfg(feature = "bad-cache")]
-        bc: &mut lru::LruCache<std::path::PathBuf, ()>
+        bc: &BadCache
     ) -> io::Result<Self> {
-        #[cfg(all(unix, feature = "bad-cache"))] {
-            Self::check_cache(parsed, bc)?;
-        }
-
+        let trailing_slash = path.rest().ends_with(b"/");
         trace!("Checking for potential path traversal...");
-        let p_buf = path::extend_dist(Path::new(dist).to_path_buf(), path)?;
+        let p_buf = path::extend_dist(Path::new(dist).to_path_buf(), path, paths)?;
 
         info!("Attempting to load {}", p_buf.display());
-        #[cfg(all(feature = "bad-cache", not(unix)))] {
-            drop(parsed);
-            trace!("Checking cache for the path");
-            if bc.get(&p_buf).is_some() {
-                debug!("Found in the `bad-cache`, rejecting request");
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Path does not exist"));
-            }
+        // checked once decoded, as that is how paths are remembered whatever their encoding in the request
+        #[cfg(feature = "bad-cache")]
+        if bc.contains(p_buf.strip_prefix(dist).unwrap(/* infallible, built upon `dist` */)) {
+            debug!("Found in the `bad-cache`, rejecting request");
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Path does not exist"));
         }
 
-        match LazyFile::new(p_buf.as_path()) {
-            Ok(file) => {
-                let mime = p_buf.as_path().extension()
-                    .and_then(|ext| ext.to_str())
-                    .and_then(|ext| mime::from_ext(ext))
-                    .unwrap_or("application/octet-stream");
-                Ok(Self {
-                    #[cfg(feature = "reload")]
-                    reload: Reload::new(p_buf),
-                    file,
-                    mime
-                })
-            },
-            Err(err) => {
-                #[cfg(feature = "bad-cache")] {
-                    debug!("Path did not exist, adding to the `bad-cache`");
-
-                    bc.put(p_buf.strip_prefix(dist).unwrap(/* infallible */).to_path_buf(), ());
-                }
-                Err(err)
+        if p_buf.is_dir() {
+            if let Some(confine) = confine {
+                confine.check(&p_buf)?;
+            }
+            if !trailing_slash {
+                return Ok(Self::Directory(p_buf));
             }
+
+            // directories are left out of the `bad-cache`, as an index file may yet be added
+            return match paths.index_files.iter().map(|name| p_buf.join(name)).find(|index| index.is_file()) {
+                Some(index) => DistReload::open(
+                    dist, index, confine,
+                    #[cfg(feature = "bad-cache")]
+                    bc
+                ).map(Self::File),
+                None if paths.listing => Ok(Self::Listing(p_buf)),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "Directory has no index file"))
+            };
+        }
+        if trailing_slash {
+            // not cached either, the path without the slash may well exist
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Not a directory"));
+        }
+
+        DistReload::open(
+            dist, p_buf, confine,
+            #[cfg(feature = "bad-cache")]
+            bc
+        ).map(Self::File)
+    }
+    /// Whether this entry may route elsewhere now that `changed` was created, removed or renamed.
+    #[cfg(feature = "reload")]
+    fn depends_on(&self, changed: &Path) -> bool {
+        match self {
+            // the file itself or a directory above it, or one of its precompressed siblings
+            Self::File(d_re) => d_re.file.path.starts_with(changed) || changed.with_extension("") == d_re.file.path,
+            Self::Directory(dir) => dir.starts_with(changed),
+            // an index file may have been added to the directory
+            Self::Listing(dir) => dir.starts_with(changed) || changed.parent() == Some(dir.as_path())
         }
     }
 }
 
-#[cfg(feature = "reload")]
-use core::sync::atomic::{AtomicU64, Ordering};
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conf::HeaderRuleConf;
+    use std::fs;
 
-#[cfg(feature = "reload")]
-struct Reload<P> {
-    last_modified: AtomicU64,
-    path: P
-}
+    /// A fresh directory named after the test, holding `files` by their path within it
+    fn temp_dist(name: &str, files: &[&str]) -> PathBuf {
+        let dist = std::env::temp_dir().join(format!("test-site-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dist);
+        for file in files {
+            let path = dist.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, file).unwrap();
+        }
+        fs::create_dir_all(&dist).unwrap();
+        dist
+    }
 
-#[cfg(feature = "reload")]
-impl<P: AsRef<Path> + core::fmt::Debug> Reload<P> {
-    #[must_use]
-    pub const fn new(path: P) -> Self {
-        Self { 
-            path, 
-            last_modified: AtomicU64::new(0) 
+    /// The defaults, serving `index.html` and everything else from `dist`
+    fn conf(dist: &Path) -> ServerConf {
+        let mut conf = ServerConf::for_tests(dist.join("index.html").to_str().unwrap());
+        conf.dist = Box::leak(dist.to_str().unwrap().to_owned().into_boxed_str());
+        conf
+    }
+
+    fn mount(prefix: &str, host: Option<&str>, dist: PathBuf) -> MountConf {
+        MountConf {
+            prefix: prefix.to_owned(),
+            host: host.map(str::to_owned),
+            dist,
+            index: None,
+            fallback: None,
+            headers: BTreeMap::new()
         }
     }
 
-    #[instrument(
-        name = "maybe-refresh-file", 
-        skip_all, 
-        fields(path = tracing::field::debug(&self.path)), 
-        err(Debug, level = Level::DEBUG),
-        level = Level::DEBUG
-    )]
-    pub async fn maybe(&self, file: &LazyFile) -> io::Result<()> {
-        macro_rules! extract {
-            ($fallible:expr, $ctx:literal) => {
-                match $fallible {
-                    Ok(__res) => __res,
-                    Err(__err) => {
-                        warn!("{}, continuing anyways. Reason: {__err:?}", $ctx);
-                        return Ok(())
-                    }
-                }
-            }
+    fn site(conf: ServerConf) -> &'static Site {
+        Box::leak(Box::new(Site::new(Box::leak(Box::new(conf))).unwrap()))
+    }
+
+    /// Respond to the request with `head`, its line and any headers without the blank line ending them
+    fn respond(site: &'static Site, head: &str) -> Response {
+        let raw: &'static str = format!("{head}\r\n\r\n").leak();
+        let req = Request::parse(raw.as_bytes()).unwrap();
+        block_on(site.respond(&req))
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn headers<'r>(res: &'r Response, name: &str) -> Vec<&'r str> {
+        res.headers.iter().filter(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| &**value).collect()
+    }
+
+    #[test]
+    fn mount_headers_replace_rules() {
+        let dist = temp_dist("mount-headers", &["index.html", "admin/index.html", "admin/sw.js"]);
+        let mut conf = conf(&dist);
+        let mut admin = mount("/admin/", None, dist.join("admin"));
+        admin.headers.insert("Service-Worker-Allowed".to_owned(), "/admin/".to_owned());
+        admin.headers.insert("Cache-Control".to_owned(), "no-store".to_owned());
+        conf.mounts.push(admin);
+        conf.headers.push(HeaderRuleConf {
+            path: Some(glob::Glob::new("/**/sw.js").unwrap()),
+            mime: None,
+            presets: vec![rules::Preset::ServiceWorker],
+            set: BTreeMap::new()
+        });
+        let site = site(conf);
+
+        let res = respond(site, "GET /admin/sw.js HTTP/1.1");
+        assert_eq!(res.status, Status::OK);
+        assert_eq!(headers(&res, "Service-Worker-Allowed"), ["/admin/"]);
+        assert_eq!(headers(&res, "Cache-Control"), ["no-store"]);
+
+        // outside of the mount only the rule applies
+        let res = respond(site, "GET /index.html HTTP/1.1");
+        assert_eq!(headers(&res, "Cache-Control"), ["no-cache"]);
+        let _ = fs::remove_dir_all(dist);
+    }
+
+    #[test]
+    fn redirects_within_the_site() {
+        let dist = temp_dist("add-slash", &["index.html", "assets/main.css", "app/index.html"]);
+        let mut conf = conf(&dist);
+        conf.mounts.push(mount("/app/", None, dist.join("app")));
+        let site = site(conf);
+
+        for (head, location) in [
+            ("GET /assets?v=1 HTTP/1.1", "/assets/?v=1"),
+            ("GET //assets HTTP/1.1", "/assets/"),
+            ("GET ///assets HTTP/1.1", "/assets/"),
+            ("GET //app HTTP/1.1", "/app/")
+        ] {
+            let res = respond(site, head);
+            assert_eq!(res.status, Status::MOVED_PERMANENTLY, "{head}");
+            assert_eq!(headers(&res, "Location"), [location], "{head}");
+        }
+        let _ = fs::remove_dir_all(dist);
+    }
+
+    #[test]
+    fn falls_back_for_navigations() {
+        let dist = temp_dist("fallback", &["index.html", "assets/main.css", "app/index.html"]);
+        let glob = |pattern: &str| glob::Glob::new(pattern).unwrap();
+        let mut conf = conf(&dist);
+        conf.fallback = Some(FallbackConf { exclude: vec![glob("/api/**")], ..FallbackConf::default() });
+        let mut app = mount("/app/", None, dist.join("app"));
+        app.fallback = Some(FallbackConf { include: vec![glob("/app/dash/**")], exclude: Vec::new() });
+        conf.mounts.push(app);
+        let site = site(conf);
+
+        let html = "Accept: text/html,application/xhtml+xml,*/*;q=0.8";
+        let respond = |target: &str, accept: &str| {
+            let res = respond(site, &format!("GET {target} HTTP/1.1\r\n{accept}"));
+            (res.status.code(), String::from_utf8_lossy(res.body.as_bytes()).into_owned())
+        };
+
+        assert_eq!(respond("/users/42", html), (200, "index.html".to_owned()));
+        assert_eq!(respond("/users/my%20profile?tab=1", html), (200, "index.html".to_owned()));
+        assert_eq!(respond("/app/dash/users", html), (200, "app/index.html".to_owned()));
+        // existing files are served as usual
+        assert_eq!(respond("/assets/main.css", html), (200, "assets/main.css".to_owned()));
+
+        for (target, accept) in [
+            // not navigations
+            ("/users/42", ""),
+            ("/users/42", "Accept: */*"),
+            ("/users/42", "Accept: application/json"),
+            ("/missing.js", html),
+            ("/app%2Ejs", html),
+            // excluded, or not included
+            ("/api/users", html),
+            ("/ap%69/users", html),
+            ("/app/settings", html)
+        ] {
+            assert_eq!(respond(target, accept).0, 404, "{target} {accept}");
         }
-        let metadata = extract!(fs::metadata(self.path.as_ref()).await, "Could not read file metadata");
-        let modified = extract!(
-            metadata.modified().and_then(|dur| dur
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))),
-            "Could not read file last modified timestamp"
-        ).as_secs();
+        // only paths which do not exist fall back, not those we refuse
+        assert_eq!(respond("/users/a%00b", html).0, 400);
+        let _ = fs::remove_dir_all(dist);
+    }
 
-        let last_modified = self.last_modified.swap(modified, Ordering::AcqRel);
+    #[test]
+    fn routes_by_host_and_prefix() {
+        let dist = temp_dist("mounts", &[
+            "index.html", "x.js", "app/index.html", "app/x.js", "admin/index.html", "admin/x.js", "docs/x.js"
+        ]);
+        let mut conf = conf(&dist);
+        conf.mounts.push(mount("/app/", None, dist.join("app")));
+        conf.mounts.push(mount("/", Some("admin.test"), dist.join("admin")));
+        conf.mounts.push(mount("/app/", Some("docs.test"), dist.join("docs")));
+        let site = site(conf);
 
-        if modified > last_modified {
-            info!("File changed, reloading...");
-            tokio::fs::read(&self.path).await.map(|re_file| file.replace_src(re_file))?
-        } else {
-            Ok(())
+        let respond = |target: &str, host: &str| {
+            let res = respond(site, &format!("GET {target} HTTP/1.1\r\nHost: {host}"));
+            match res.status {
+                Status::OK => String::from_utf8_lossy(res.body.as_bytes()).into_owned(),
+                status => format!("{} {:?}", status.code(), headers(&res, "Location"))
+            }
+        };
+
+        assert_eq!(respond("/x.js", "localhost"), "x.js");
+        assert_eq!(respond("/app/x.js", "localhost"), "app/x.js");
+        assert_eq!(respond("/app/", "localhost"), "app/index.html");
+        assert_eq!(respond("/app", "localhost"), "301 [\"/app/\"]");
+        assert_eq!(respond("/application.js", "localhost"), "404 []");
+
+        // a host's root takes everything for it, ignoring the port and case
+        assert_eq!(respond("/x.js", "admin.test:8080"), "admin/x.js");
+        assert_eq!(respond("/", "ADMIN.test"), "admin/index.html");
+        assert_eq!(respond("/app/x.js", "admin.test"), "404 []");
+
+        // a host's mounts come first, the default ones serve the rest
+        assert_eq!(respond("/app/x.js", "docs.test"), "docs/x.js");
+        assert_eq!(respond("/x.js", "docs.test"), "x.js");
+        assert_eq!(respond("/app/x.js", "other.test"), "app/x.js");
+        assert_eq!(respond("/", "other.test"), "index.html");
+        let _ = fs::remove_dir_all(dist);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confines_swapped_directories() {
+        let dist = temp_dist("confine-swap", &["index.html", "docs/guide.md", "assets/a.js"]);
+        let outside = temp_dist("confine-swap-outside", &["secret.txt"]);
+        let mut conf = conf(&dist);
+        conf.paths = PathsConf { confine: true, listing: true, ..PathsConf::default() };
+        let site = site(conf);
+
+        let res = respond(site, "GET /docs/ HTTP/1.1\r\nAccept: application/json");
+        assert_eq!(res.status, Status::OK);
+        assert!(String::from_utf8_lossy(res.body.as_bytes()).contains("guide.md"));
+
+        // the listing is routed to already, though the directory is now elsewhere
+        fs::remove_dir_all(dist.join("docs")).unwrap();
+        std::os::unix::fs::symlink(&outside, dist.join("docs")).unwrap();
+        let res = respond(site, "GET /docs/ HTTP/1.1\r\nAccept: application/json");
+        assert_eq!(res.status, Status::FORBIDDEN);
+        assert!(!String::from_utf8_lossy(res.body.as_bytes()).contains("secret.txt"));
+
+        // files are read from where they resolved to when routed
+        let paths: &'static PathsConf = Box::leak(Box::new(PathsConf { confine: true, ..PathsConf::default() }));
+        let dir = path::Confine::new(dist.to_str().unwrap(), paths).unwrap();
+        let file = Variants::new(dist.join("assets/a.js"), Some(&dir)).unwrap();
+        fs::remove_dir_all(dist.join("assets")).unwrap();
+        std::os::unix::fs::symlink(&outside, dist.join("assets")).unwrap();
+        fs::rename(outside.join("secret.txt"), outside.join("a.js")).unwrap();
+        assert_eq!(block_on(file.file.load()).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let _ = fs::remove_dir_all(dist);
+        let _ = fs::remove_dir_all(outside);
+    }
+
+    #[test]
+    fn routes_spellings_alike() {
+        let dist = temp_dist("spellings", &["app.js", "my file.js"]);
+        let handler = DistHandler::new(
+            Box::leak(dist.to_str().unwrap().to_owned().into_boxed_str()),
+            Box::leak(Box::new(PathsConf::default())),
+            &BadCacheConf::default()
+        );
+        let route = |path: &'static str| handler.try_route(PathIter::new(path.as_bytes())).unwrap().unwrap();
+
+        let app = route("app.js");
+        for path in ["app%2Ejs", "%61pp.js", "/app.js", "//app.js"] {
+            assert!(Arc::ptr_eq(&app, &route(path)), "{path}");
         }
+        assert!(Arc::ptr_eq(&route("my%20file.js"), &route("my%20fil%65.js")));
+        let _ = fs::remove_dir_all(dist);
     }
-}
 
-#[inline]
-async fn write_status(mut stream: TcpStream, status: &'static str, msg: &'static str) -> io::Result<usize> {
-    stream.write_vectored(&[
-        IoSlice::new(b"HTTP/1.1 "),
-        IoSlice::new(status.as_bytes()),
-        IoSlice::new(b" "),
-        IoSlice::new(msg.as_bytes()),
-        IoSlice::new(b"\r\nContent-Type: text/plain\r\n\r\n"),
-        IoSlice::new(msg.as_bytes())
-    ]).await
+    #[test]
+    fn loads_files_once_off_the_worker() {
+        let dist = temp_dist("load", &["app.js"]);
+        let file: &'static LazyFile = Box::leak(Box::new(LazyFile::new(dist.join("app.js"), false).unwrap()));
+
+        // both wait on the one read, without either blocking the only worker
+        let (first, second) = block_on(async {
+            let (first, second) = (tokio::spawn(file.load()), tokio::spawn(file.load()));
+            (first.await.unwrap().unwrap(), second.await.unwrap().unwrap())
+        });
+        assert_eq!(&**first.bytes(), b"app.js");
+        assert!(Arc::ptr_eq(&first, &second));
+
+        fs::remove_file(dist.join("app.js")).unwrap();
+        assert!(Arc::ptr_eq(&first, &block_on(file.load()).unwrap()));
+        let _ = fs::remove_dir_all(dist);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compresses_once_off_the_worker() {
+        let contents: &'static Contents = Box::leak(Box::new(Contents::new(b"console.log(1);\n".repeat(1024), None)));
+
+        let (first, second) = block_on(async {
+            let first = tokio::spawn(contents.encoded(Encoding::Gzip));
+            let second = tokio::spawn(contents.encoded(Encoding::Gzip));
+            (first.await.unwrap().unwrap(), second.await.unwrap().unwrap())
+        });
+        assert!(first.bytes().len() < contents.bytes().len());
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(block_on(contents.encoded(Encoding::Identity)).is_none());
+    }
+
+    #[cfg(feature = "reload")]
+    #[test]
+    fn invalidates_changed_routes() {
+        use watch::{Change, Changes};
+
+        let root = temp_dist("invalidate", &["app.js", "other.js", "assets/a.js", "docs/guide.md"]);
+        let dist: &'static str = Box::leak(root.to_str().unwrap().to_owned().into_boxed_str());
+        let paths: &'static PathsConf = Box::leak(Box::new(PathsConf { listing: true, ..PathsConf::default() }));
+        let handler = DistHandler::new(dist, paths, &BadCacheConf::default());
+
+        let route = |path: &'static str| handler.try_route(PathIter::new(path.as_bytes())).map(Option::unwrap);
+        let missing = |path| matches!(route(path), Err(err) if err.kind() == io::ErrorKind::NotFound);
+        let changed = |paths: &[&str]| {
+            let mut changes = Changes::default();
+            changes.paths.extend(paths.iter().map(|path| (root.join(path), Change::Structure)));
+            handler.invalidate(&changes);
+        };
+        let other = route("other.js").unwrap();
+
+        // a created file, which was remembered as missing
+        assert!(missing("late.js"));
+        fs::write(root.join("late.js"), "late").unwrap();
+        changed(&["late.js"]);
+        assert!(matches!(*route("late.js").unwrap(), DistEntry::File(_)));
+
+        // a precompressed sibling of a file already routed to
+        assert!(matches!(&*route("app.js").unwrap(), DistEntry::File(d_re) if d_re.file.br.is_none()));
+        fs::write(root.join("app.js.br"), "br").unwrap();
+        changed(&["app.js.br"]);
+        assert!(matches!(&*route("app.js").unwrap(), DistEntry::File(d_re) if d_re.file.br.is_some()));
+
+        // a removed directory, along with the files within it
+        assert!(route("assets/a.js").is_ok());
+        fs::remove_dir_all(root.join("assets")).unwrap();
+        changed(&["assets"]);
+        assert!(missing("assets/a.js"));
+
+        // an index added to a directory which was listed
+        assert!(matches!(*route("docs/").unwrap(), DistEntry::Listing(_)));
+        fs::write(root.join("docs/index.html"), "docs").unwrap();
+        changed(&["docs/index.html"]);
+        assert!(matches!(*route("docs/").unwrap(), DistEntry::File(_)));
+
+        // none of which concerned the other file
+        assert!(Arc::ptr_eq(&other, &route("other.js").unwrap()));
+
+        // events were lost, so anything may have changed
+        handler.invalidate(&Changes { rescan: true, ..Changes::default() });
+        assert!(handler.seen.get(PathIter::new(b"other.js")).is_none());
+        let _ = fs::remove_dir_all(root);
+    }
 }
@@ -2,20 +2,44 @@
 use swift_check::{search, eq, any};
 use core::iter::FusedIterator;
 use core::fmt;
+use std::borrow::Cow;
 
 #[derive(Debug)]
 pub enum ParseError<'src> {
     Expected { expected: &'static str, found: &'src [u8] },
     EndOfInput,
-    Complete
+    Complete,
+    /// The request head was not terminated by an empty line, more bytes are required
+    Incomplete,
+    /// The method was not a valid token
+    InvalidMethod(&'src [u8]),
+    /// The request target was not in origin, absolute, or asterisk form
+    InvalidTarget(&'src [u8]),
+    /// The protocol was not of the form `HTTP/x.y`
+    InvalidVersion(&'src [u8]),
+    /// The protocol was well formed, though not a version of HTTP/1
+    UnsupportedVersion(&'src [u8]),
+    /// A header line was malformed, or used obsolete line folding
+    InvalidHeader(&'src [u8])
 }
 
 impl<'src> fmt::Display for ParseError<'src> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        macro_rules! found {
+            ($variant:literal, $found:ident) => {
+                write!(f, concat!("ParseError {{ ", $variant, ": {:?} }}"), String::from_utf8_lossy($found))
+            }
+        }
         match self {
             Self::Expected { expected, found } => write!(f, "ParseError {{ expected: {expected}, found: {found:?} }}"),
-            Self::EndOfInput => f.write_str("ParseError {{ EndOfInput }}"),
-            Self::Complete => f.write_str("ParseError {{ Complete }}")
+            Self::EndOfInput => f.write_str("ParseError { EndOfInput }"),
+            Self::Complete => f.write_str("ParseError { Complete }"),
+            Self::Incomplete => f.write_str("ParseError { Incomplete }"),
+            Self::InvalidMethod(found) => found!("InvalidMethod", found),
+            Self::InvalidTarget(found) => found!("InvalidTarget", found),
+            Self::InvalidVersion(found) => found!("InvalidVersion", found),
+            Self::UnsupportedVersion(found) => found!("UnsupportedVersion", found),
+            Self::InvalidHeader(found) => found!("InvalidHeader", found)
         }
     }
 }
@@ -46,27 +70,27 @@ macro_rules! tag {
     }}
 }
 
-/// Takes until the `byte` or any whitespace
+/// Takes until the `byte` or a space
 #[inline(always)]
 fn take_until<'src>(raw: &'src [u8], byte: u8) -> PResult<'src, &'src [u8]> {
     // we search the first 32 bytes, if we don't find a match and there's at least a reg width remaining we yield to
     // SIMD
     let normal_search = core::cmp::min(32, raw.len());
-    match (&raw[..normal_search]).iter().position(|o_byte| (*o_byte == byte) | matches!(o_byte, b' ' | b'~')) {
+    match raw[..normal_search].iter().position(|o_byte| (*o_byte == byte) | (*o_byte == b' ')) {
         Some(pos) => Ok((&raw[pos..], &raw[..pos])),
         None => {
             if raw.len() == normal_search { return Err(ParseError::EndOfInput); }
             
             if raw.len() >= (swift_check::arch::WIDTH + 32) {
-                match search(&raw[normal_search..], any!(eq(byte), eq(b' '), eq(b'~'))) {
-                    Some(pos) => Ok((&raw[normal_search + pos + 1..], &raw[..normal_search + pos])),
+                match search(&raw[normal_search..], any!(eq(byte), eq(b' '))) {
+                    Some(pos) => Ok((&raw[normal_search + pos..], &raw[..normal_search + pos])),
                     _ => Err(ParseError::EndOfInput)
                 }
             } else {
-                match (&raw[normal_search..])
+                match raw[normal_search..]
                     .iter()
-                    .position(|o_byte| (*o_byte == byte) | matches!(o_byte, b' ' | b'~')) {
-                    Some(pos) => Ok((&raw[normal_search + pos + 1..], &raw[..normal_search + pos])),
+                    .position(|o_byte| (*o_byte == byte) | (*o_byte == b' ')) {
+                    Some(pos) => Ok((&raw[normal_search + pos..], &raw[..normal_search + pos])),
                     None => Err(ParseError::EndOfInput)
                 }
             }
@@ -94,21 +118,23 @@ macro_rules! ident_cond {
 #[inline(always)]
 fn take_ident<'src>(raw: &'src [u8]) -> PResult<'src, &'src [u8]> {
     let normal_search = core::cmp::min(32, raw.len());
-    match (&raw[..normal_search]).iter().position(|byte| matches!(byte, b' ' | b'\r' | b'/')) {
-        Some(pos) if pos == 0 => Err(ParseError::Expected { expected: "ident", found: raw }),
+    match raw[..normal_search].iter().position(|byte| matches!(byte, b' ' | b'\r' | b'/')) {
+        Some(0) => Err(ParseError::Expected { expected: "ident", found: raw }),
         Some(pos) => Ok((&raw[pos..], &raw[..pos])),
         None => {
-            if raw.len() == normal_search { return Err(ParseError::EndOfInput); }
+            if raw.is_empty() { return Err(ParseError::EndOfInput); }
+            // the ident runs to the end of the input
+            if raw.len() == normal_search { return Ok((&raw[raw.len()..], raw)); }
 
             if raw.len() >= (swift_check::arch::WIDTH + 32) {
                 match search(&raw[normal_search..], ident_cond!()) {
                     Some(pos) => Ok((&raw[pos + normal_search..], &raw[..pos + normal_search])),
-                    None => Ok(("".as_bytes(), raw)) 
+                    None => Ok((&raw[raw.len()..], raw)) 
                 }         
             } else {
-                match (&raw[normal_search..]).iter().position(|byte| matches!(byte, b' ' | b'\r' | b'/'))  {
+                match raw[normal_search..].iter().position(|byte| matches!(byte, b' ' | b'\r' | b'/'))  {
                     Some(pos) => Ok((&raw[normal_search + pos..], &raw[..normal_search + pos])),
-                    None => Ok(("".as_bytes(), raw))
+                    None => Ok((&raw[raw.len()..], raw))
                 }
             }
         }
@@ -168,70 +194,26 @@ impl<'src> PathIter<'src> {
         self.remainder.first().copied()
     }
 
-    #[inline(always)]
-    #[must_use]
-    pub fn next_is(&self, other: u8) -> bool {
-        self.peek() == Some(other)
-    }
-
-    #[inline(always)]
-    pub fn take(&mut self, other: u8) {
-        if self.next_is(other) {
-            self.remainder = &self.remainder[1..];
-        }
-    }
-
-    #[inline(always)]
+    /// The unparsed portion of the path, excluding anything following it in the request.
+    #[inline]
     #[must_use]
-    pub fn next_is_opt(&self, other: Option<&u8>) -> bool {
-        match (self.remainder.first(), other) {
-            (Some(b'/'), Some(other)) => match self.remainder.get(1) {
-                Some(our) => our == other,
-                None => false
-            },
-            (Some(our), Some(other)) => our == other,
-            _ => false
+    pub fn rest(&self) -> &'src [u8] {
+        match self.remainder.iter().position(|byte| *byte == b' ') {
+            Some(end) => &self.remainder[..end],
+            None => self.remainder
         }
     }
 
+    /// Advance past the longest common prefix of the remaining path and `other`, returning its length.
     #[inline(always)]
-    #[must_use]
-    pub fn move_match<'o>(&mut self, other: &'o [u8]) -> &'o [u8] {
-        use core::cmp::Ordering;
-        macro_rules! find_dif {
-            ($this:ident, $res:ident, $other:ident, min: $m_len:ident, eaten: $eaten:ident, $l:lifetime) => {{
-                if $m_len == 0 {
-                    break $l;
-                }
-                for __i in 0..$m_len {
-                    if $res[__i] != $other[$eaten.wrapping_add(__i)] {
-                        $this.remainder = &$this.remainder[__i..];
-                        $eaten = ($eaten.wrapping_add(__i));
-                        break $l;
-                    }
-                }
-                $this.remainder = &$this.remainder[$m_len..];
-                $eaten = $eaten.wrapping_add($m_len);
-            }}
-        }
-
-        if other.is_empty() {
-            return other;
-        }
-
-        let mut eaten = 0usize;
-        'outer: while other.len() > eaten {
-            let Ok((_, res)) = self.parse_next() else { break };
-            let (r_len, o_len) = (res.len(), other.len());
-            match r_len.cmp(&(o_len - eaten)) {
-                Ordering::Less => find_dif!(self, res, other, min: r_len, eaten: eaten, 'outer),
-                Ordering::Equal | Ordering::Greater => find_dif!(self, res, other, min: o_len, eaten: eaten, 'outer)
-            }
-        }
-
-        self.take(b'/');
-
-        &other[..eaten]
+    pub fn match_prefix(&mut self, other: &[u8]) -> usize {
+        // `other` never contains a space, so we cannot match beyond the end of the path.
+        let matched = self.remainder.iter()
+            .zip(other)
+            .take_while(|(ours, theirs)| ours == theirs)
+            .count();
+        self.remainder = &self.remainder[matched..];
+        matched
     }
 
     #[allow(dead_code)]
@@ -239,7 +221,7 @@ impl<'src> PathIter<'src> {
     #[inline]
     #[must_use]
     pub fn peek_ref(&self) -> Option<&'src [u8]> {
-        if self.remainder.len() > 0 {
+        if !self.remainder.is_empty() {
             Some(&self.remainder[..1])
         } else {
             None
@@ -269,7 +251,9 @@ impl<'src> PathIter<'src> {
         match take_path_seg(self.remainder) {
             res @ Ok(_) => res,
             Err(_) if self.next_known_terminal() => Err(ParseError::Complete),
-            Err(_ /* No further path sep, take ident */) => match take_ident(self.remainder) {
+            Err(_ /* No further path sep, take ident */) => match take_ident(
+                tag!(slash is "/")(self.remainder).map_or(self.remainder, |(rem, _)| rem)
+            ) {
                 res @ Ok(_) => res,
                 Err(_) => Err(ParseError::Complete)
             }
@@ -297,9 +281,443 @@ impl<'src> Iterator for PathIter<'src> {
 
 impl<'src> FusedIterator for PathIter<'src> {}
 
+/// Takes a line terminated by `CRLF` (or a bare `LF`), the terminator is consumed though not included in the line.
+#[inline(always)]
+fn take_line<'src>(raw: &'src [u8]) -> PResult<'src, &'src [u8]> {
+    #[inline(always)]
+    fn split<'src>(raw: &'src [u8], pos: usize) -> PResult<'src, &'src [u8]> {
+        match (raw[pos], raw.get(pos + 1)) {
+            (b'\n', _) => Ok((&raw[pos + 1..], &raw[..pos])),
+            (_ /* \r */, Some(b'\n')) => Ok((&raw[pos + 2..], &raw[..pos])),
+            (_ /* \r */, Some(_)) => Err(ParseError::Expected { expected: "\\r\\n", found: &raw[pos..] }),
+            (_ /* \r */, None) => Err(ParseError::Incomplete)
+        }
+    }
+
+    let normal_search = core::cmp::min(32, raw.len());
+    match raw[..normal_search].iter().position(|byte| matches!(byte, b'\r' | b'\n')) {
+        Some(pos) => split(raw, pos),
+        None if raw.len() >= (swift_check::arch::WIDTH + 32) => {
+            match search(&raw[normal_search..], any!(eq(b'\r'), eq(b'\n'))) {
+                Some(pos) => split(raw, normal_search + pos),
+                None => Err(ParseError::Incomplete)
+            }
+        },
+        None => match raw[normal_search..].iter().position(|byte| matches!(byte, b'\r' | b'\n')) {
+            Some(pos) => split(raw, normal_search + pos),
+            None => Err(ParseError::Incomplete)
+        }
+    }
+}
+
+/// `tchar` from RFC 9110, the legal bytes of methods and header names
+#[inline(always)]
+#[must_use]
+pub const fn is_token(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.'
+        | b'^' | b'_' | b'`' | b'|' | b'~'
+    )
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    /// Any other syntactically valid method
+    Extension
+}
+
+impl Method {
+    #[inline]
+    fn parse(raw: &[u8]) -> PResult<'_, Self> {
+        let (rem, method) = take_until(raw, b' ').map_err(|_| ParseError::InvalidMethod(raw))?;
+        let method = Self::from_bytes(method).ok_or(ParseError::InvalidMethod(method))?;
+
+        tag!(space is " ")(rem).map(|(rem, _)| (rem, method))
+    }
+
+    /// The method named by `raw`, `None` if it is not a valid token
+    #[inline]
+    #[must_use]
+    pub fn from_bytes(raw: &[u8]) -> Option<Self> {
+        Some(match raw {
+            b"GET" => Self::Get,
+            b"HEAD" => Self::Head,
+            b"POST" => Self::Post,
+            b"PUT" => Self::Put,
+            b"DELETE" => Self::Delete,
+            b"CONNECT" => Self::Connect,
+            b"OPTIONS" => Self::Options,
+            b"TRACE" => Self::Trace,
+            b"PATCH" => Self::Patch,
+            b"" => return None,
+            other if other.iter().all(|byte| is_token(*byte)) => Self::Extension,
+            _ => return None
+        })
+    }
+
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Connect => "CONNECT",
+            Self::Options => "OPTIONS",
+            Self::Trace => "TRACE",
+            Self::Patch => "PATCH",
+            Self::Extension => "EXTENSION"
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+    /// Never parsed from a request line, HTTP/2 requests are decoded from frames
+    Http2
+}
+
+impl Version {
+    #[inline]
+    fn parse(raw: &[u8]) -> Result<Self, ParseError<'_>> {
+        let (rem, _) = tag!(http is "HTTP/")(raw).map_err(|_| ParseError::InvalidVersion(raw))?;
+        match rem {
+            b"1.1" => Ok(Self::Http11),
+            b"1.0" => Ok(Self::Http10),
+            [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                Err(ParseError::UnsupportedVersion(raw))
+            },
+            _ => Err(ParseError::InvalidVersion(raw))
+        }
+    }
+
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http10 => "HTTP/1.0",
+            Self::Http11 => "HTTP/1.1",
+            Self::Http2 => "HTTP/2"
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Header<'src> {
+    pub name: &'src [u8],
+    pub value: &'src [u8]
+}
+
+impl<'src> Header<'src> {
+    #[inline]
+    fn parse(line: &'src [u8]) -> Result<Self, ParseError<'src>> {
+        // obsolete line folding (RFC 9112 section 5.2), we are allowed to reject this outright
+        if matches!(line.first(), Some(b' ' | b'\t')) {
+            return Err(ParseError::InvalidHeader(line));
+        }
+
+        let Some(colon) = line.iter().position(|byte| *byte == b':') else {
+            return Err(ParseError::InvalidHeader(line));
+        };
+
+        let name = &line[..colon];
+        if name.is_empty() || !name.iter().all(|byte| is_token(*byte)) {
+            return Err(ParseError::InvalidHeader(line));
+        }
+
+        Ok(Self { name, value: line[colon + 1..].trim_ascii() })
+    }
+}
+
+/// The headers of a request, in the order they were received
+#[derive(Debug, Default, Clone)]
+pub struct Headers<'src> {
+    inner: Vec<Header<'src>>
+}
+
+impl<'src> Headers<'src> {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self { inner: Vec::with_capacity(16) }
+    }
+
+    #[inline]
+    pub fn push(&mut self, name: &'src [u8], value: &'src [u8]) {
+        self.inner.push(Header { name, value });
+    }
+
+    /// Get the first value of the header `name` (case-insensitive)
+    #[inline]
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&'src [u8]> {
+        self.get_all(name).next()
+    }
+
+    /// Get the first value of the header `name` (case-insensitive) if it is valid UTF-8
+    #[allow(dead_code)]
+    #[inline]
+    #[must_use]
+    pub fn get_str(&self, name: &str) -> Option<&'src str> {
+        self.get(name).and_then(|value| core::str::from_utf8(value).ok())
+    }
+
+    /// Get every value of the header `name` (case-insensitive)
+    #[inline]
+    pub fn get_all<'h>(&'h self, name: &'h str) -> impl Iterator<Item = &'src [u8]> + 'h {
+        self.inner.iter()
+            .filter(move |header| header.name.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|header| header.value)
+    }
+
+    #[allow(dead_code)]
+    #[inline]
+    pub fn iter(&self) -> core::slice::Iter<'_, Header<'src>> {
+        self.inner.iter()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    #[allow(dead_code)]
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
 #[inline]
-pub fn get_req_path<'src>(raw: &'src [u8]) -> Result<PathIter<'src>, ParseError<'src>> {
-    tag!(get_method is "GET /")(raw).map(|(rem, _)| PathIter::new(rem))
+#[must_use]
+const fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None
+    }
+}
+
+/// Decode the percent-encoded octets of `raw` (RFC 3986 section 2.1), only allocating if there are any.
+///
+/// Yields `None` if an escape is malformed, a `%` must be followed by two hex digits.
+#[must_use]
+pub fn percent_decode(raw: &[u8]) -> Option<Cow<'_, [u8]>> {
+    let Some(first) = raw.iter().position(|byte| *byte == b'%') else {
+        return Some(Cow::Borrowed(raw));
+    };
+
+    let mut decoded = Vec::with_capacity(raw.len());
+    decoded.extend_from_slice(&raw[..first]);
+    let mut rem = &raw[first..];
+
+    while let Some((&byte, rest)) = rem.split_first() {
+        if byte == b'%' {
+            let [hi, lo, ..] = rest else { return None };
+            decoded.push(hex_value(*hi)? << 4 | hex_value(*lo)?);
+            rem = &rest[2..];
+        } else {
+            decoded.push(byte);
+            rem = rest;
+        }
+    }
+
+    Some(Cow::Owned(decoded))
+}
+
+/// Decode a component of an `application/x-www-form-urlencoded` query, where `+` stands for a space. Malformed escapes
+/// are left as they were sent, browsers do the same when building these.
+#[must_use]
+fn form_decode(raw: &[u8]) -> Cow<'_, [u8]> {
+    if raw.contains(&b'+') {
+        let spaced: Vec<u8> = raw.iter().map(|byte| if *byte == b'+' { b' ' } else { *byte }).collect();
+        match percent_decode(&spaced) {
+            Some(Cow::Owned(decoded)) => Cow::Owned(decoded),
+            _ => Cow::Owned(spaced)
+        }
+    } else {
+        percent_decode(raw).unwrap_or(Cow::Borrowed(raw))
+    }
+}
+
+/// The query of a request target, without the leading `?`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Query<'src> {
+    raw: &'src [u8]
+}
+
+impl<'src> Query<'src> {
+    /// The query as it was sent, still percent-encoded
+    #[inline]
+    #[must_use]
+    pub const fn raw(&self) -> &'src [u8] {
+        self.raw
+    }
+
+    /// The decoded `name=value` pairs of the query, in the order they were sent. A pair without `=` has an empty value.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn pairs(&self) -> impl Iterator<Item = (Cow<'src, [u8]>, Cow<'src, [u8]>)> {
+        self.raw.split(|byte| *byte == b'&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.iter().position(|byte| *byte == b'=') {
+                Some(eq) => (form_decode(&pair[..eq]), form_decode(&pair[eq + 1..])),
+                None => (form_decode(pair), Cow::Borrowed(&[][..]))
+            })
+    }
+
+    /// The decoded value of the first pair named `name`
+    #[allow(dead_code)]
+    #[inline]
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<Cow<'src, [u8]>> {
+        self.pairs().find(|(key, _)| key.as_ref() == name.as_bytes()).map(|(_, value)| value)
+    }
+}
+
+/// A parsed HTTP/1 request head
+#[derive(Debug)]
+pub struct Request<'src> {
+    pub method: Method,
+    pub target: &'src [u8],
+    pub version: Version,
+    pub headers: Headers<'src>,
+    /// The number of bytes the request head occupied, including the terminating empty line
+    pub len: usize
+}
+
+impl<'src> Request<'src> {
+    pub fn parse(raw: &'src [u8]) -> Result<Self, ParseError<'src>> {
+        let (rem, line) = take_line(raw)?;
+        let (line_rem, method) = Method::parse(line)?;
+
+        let (version, target) = match line_rem.iter().rposition(|byte| *byte == b' ') {
+            Some(space) => (&line_rem[space + 1..], &line_rem[..space]),
+            None => return Err(ParseError::InvalidVersion(line_rem))
+        };
+        let version = Version::parse(version)?;
+        Self::check_target(target)?;
+
+        let mut headers = Headers::new();
+        let mut rem = rem;
+        loop {
+            let (next, line) = take_line(rem)?;
+            rem = next;
+            if line.is_empty() {
+                break;
+            }
+            let header = Header::parse(line)?;
+            headers.push(header.name, header.value);
+        }
+
+        Ok(Self { method, target, version, headers, len: raw.len() - rem.len() })
+    }
+
+    #[inline]
+    fn check_target(target: &'src [u8]) -> Result<(), ParseError<'src>> {
+        match target {
+            b"*" => Ok(()),
+            [b'/', ..] if !target.iter().any(|byte| byte.is_ascii_whitespace() || byte.is_ascii_control()) => Ok(()),
+            _ if Self::strip_authority(target).is_some() => Ok(()),
+            _ => Err(ParseError::InvalidTarget(target))
+        }
+    }
+
+    /// Strip the scheme and authority of an absolute-form target, yielding the path
+    #[inline]
+    fn strip_authority(target: &'src [u8]) -> Option<&'src [u8]> {
+        let rem = tag!(http is "http://")(target).or_else(|_| tag!(https is "https://")(target)).ok()?.0;
+        match rem.iter().position(|byte| *byte == b'/') {
+            Some(slash) => Some(&rem[slash..]),
+            None if !rem.is_empty() => Some(b"/"),
+            None => None
+        }
+    }
+
+    /// The path (and query) of the request target in origin-form, whatever form the target was sent in
+    #[inline]
+    #[must_use]
+    pub fn origin_form(&self) -> &'src [u8] {
+        match self.target {
+            [b'/', ..] => self.target,
+            b"*" => b"/",
+            absolute => Self::strip_authority(absolute).unwrap_or(b"/")
+        }
+    }
+
+    /// The `Host` header, if it is a plausible host with an optional port
+    #[inline]
+    #[must_use]
+    pub fn host(&self) -> Option<&'src str> {
+        let valid = |host: &&str| !host.is_empty() && host.bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_' | b':' | b'[' | b']'));
+        self.headers.get_str("host").map(str::trim).filter(valid)
+    }
+
+    /// The `Host` header without its port, taking care not to mistake part of an IPv6 address for one
+    #[inline]
+    #[must_use]
+    pub fn hostname(&self) -> Option<&'src str> {
+        let host = self.host()?;
+        Some(match host.rfind(':') {
+            Some(colon) if !host[colon..].contains(']') && host.starts_with('[') == host.contains(']') => {
+                &host[..colon]
+            },
+            _ => host
+        })
+    }
+
+    /// The path of the request target, excluding the leading slash, query and fragment. Segments are yielded as they
+    /// were sent, percent-encoded.
+    #[inline]
+    pub fn path(&self) -> PathIter<'src> {
+        PathIter::new(&self.origin_path()[1..])
+    }
+
+    /// The path of the request target in origin-form, including the leading slash though not the query or fragment
+    #[inline]
+    #[must_use]
+    pub fn origin_path(&self) -> &'src [u8] {
+        let target = self.origin_form();
+        let end = target.iter().position(|byte| matches!(byte, b'?' | b'#')).unwrap_or(target.len());
+        &target[..end]
+    }
+
+    /// The query of the request target, if it has one. A fragment should never be sent, though if it is it's dropped.
+    #[inline]
+    #[must_use]
+    pub fn query(&self) -> Option<Query<'src>> {
+        let target = self.origin_form();
+        let path_end = target.iter().position(|byte| matches!(byte, b'?' | b'#'))?;
+        if target[path_end] == b'#' {
+            return None;
+        }
+        let query = &target[path_end + 1..];
+        let end = query.iter().position(|byte| *byte == b'#').unwrap_or(query.len());
+        Some(Query { raw: &query[..end] })
+    }
 }
 
 #[cfg(test)]
@@ -361,4 +779,96 @@ mod tests {
 
 
     }
+
+    #[test]
+    fn request_head() {
+        let raw = b"GET /hello/world.js?v=1 HTTP/1.1\r\nHost: localhost:6969\r\nAccept:text/html \r\n\
+                    accept: */*\r\n\r\nGET / HTTP/1.1\r\n";
+        let req = Request::parse(raw).unwrap();
+
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.version, Version::Http11);
+        strcmp!(req.target, b"/hello/world.js?v=1");
+        strcmp!(opt req.headers.get("host"), b"localhost:6969");
+        strcmp!(opt req.headers.get("ACCEPT"), b"text/html");
+        assert_eq!(req.headers.get_all("accept").count(), 2);
+        strcmp!(&raw[req.len..], b"GET / HTTP/1.1\r\n");
+
+        let mut path = req.path();
+        strcmp!(opt path.next(), b"hello");
+        strcmp!(opt path.next(), b"world.js");
+        assert_eq!(path.next(), None);
+        strcmp!(path.get_parsed(), b"hello/world.js");
+        strcmp!(req.query().unwrap().raw(), b"v=1");
+    }
+
+    #[test]
+    fn request_line_forms() {
+        let req = Request::parse(b"OPTIONS * HTTP/1.0\n\n").unwrap();
+        assert_eq!((req.method, req.version), (Method::Options, Version::Http10));
+        assert!(req.path().next_known_terminal());
+
+        let req = Request::parse(b"PURGE http://localhost:6969/a/b.js HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.method, Method::Extension);
+        let mut path = req.path();
+        strcmp!(opt path.next(), b"a");
+        strcmp!(opt path.next(), b"b.js");
+    }
+
+    #[test]
+    fn query_and_fragment() {
+        let req = Request::parse(b"GET /bundle.js?v=123&name=a+b%21&flag HTTP/1.1\r\n\r\n").unwrap();
+        let mut path = req.path();
+        strcmp!(opt path.next(), b"bundle.js");
+        assert_eq!(path.next(), None);
+
+        let query = req.query().unwrap();
+        strcmp!(query.raw(), b"v=123&name=a+b%21&flag");
+        assert_eq!(query.get("v").as_deref(), Some(&b"123"[..]));
+        assert_eq!(query.get("name").as_deref(), Some(&b"a b!"[..]));
+        assert_eq!(query.get("flag").as_deref(), Some(&b""[..]));
+        assert_eq!(query.get("missing"), None);
+
+        let req = Request::parse(b"GET /a/b.js#top HTTP/1.1\r\n\r\n").unwrap();
+        let mut path = req.path();
+        strcmp!(opt path.next(), b"a");
+        strcmp!(opt path.next(), b"b.js");
+        assert_eq!(req.query(), None);
+
+        let req = Request::parse(b"GET http://localhost/?q=1#frag HTTP/1.1\r\n\r\n").unwrap();
+        assert!(req.path().next_known_terminal());
+        strcmp!(req.query().unwrap().raw(), b"q=1");
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode(b"plain.js").as_deref(), Some(&b"plain.js"[..]));
+        assert_eq!(percent_decode(b"my%20file%2Ejs").as_deref(), Some(&b"my file.js"[..]));
+        assert_eq!(percent_decode(b"%e2%9c%93").as_deref(), Some("\u{2713}".as_bytes()));
+        assert_eq!(percent_decode(b"%"), None);
+        assert_eq!(percent_decode(b"%4"), None);
+        assert_eq!(percent_decode(b"%G0"), None);
+    }
+
+    #[test]
+    fn request_errors() {
+        macro_rules! fails {
+            ($raw:literal, $pat:pat) => {
+                match Request::parse($raw) {
+                    Err($pat) => (),
+                    other => panic!("expected {} for {:?}, got {other:?}", stringify!($pat), $raw)
+                }
+            };
+        }
+
+        fails!(b"GET / HTTP/1.1\r\nHost: localhost\r\n", ParseError::Incomplete);
+        fails!(b"GET / HTTP/1.1", ParseError::Incomplete);
+        fails!(b"G(T / HTTP/1.1\r\n\r\n", ParseError::InvalidMethod(_));
+        fails!(b"GET hello HTTP/1.1\r\n\r\n", ParseError::InvalidTarget(_));
+        fails!(b"GET / HTTQ/1.1\r\n\r\n", ParseError::InvalidVersion(_));
+        fails!(b"GET / HTTP/2.0\r\n\r\n", ParseError::UnsupportedVersion(_));
+        fails!(b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n", ParseError::InvalidHeader(_));
+        fails!(b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n", ParseError::InvalidHeader(_));
+        fails!(b"GET / HTTP/1.1\r\nHost: a\r\n  b\r\n\r\n", ParseError::InvalidHeader(_));
+    }
 }
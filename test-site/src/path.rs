@@ -1,7 +1,10 @@
 use swift_check::{for_all_ensure, any, eq, range};
-use std::path::PathBuf;
-use crate::parse::PathIter;
-use std::io;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use crate::parse::{percent_decode, PathIter};
+use crate::conf::{Charset, PathsConf};
+use std::{fs, io};
+use tracing::warn;
 
 macro_rules! illegal {
     () => {
@@ -19,60 +22,278 @@ macro_rules! illegal {
     }
 }
 
+/// Push a segment of the request path onto `dist`, decoding it first.
 #[inline]
-pub fn extend_dist(mut dist: PathBuf, mut path_iter: PathIter) -> io::Result<PathBuf> {
-    let Some(first_segment) = path_iter.next() else {
+fn push_segment(dist: &mut PathBuf, segment: &[u8], policy: &PathsConf) -> io::Result<()> {
+    let Some(decoded) = percent_decode(segment) else {
         return Err(io::Error::new(
             io::ErrorKind::InvalidInput,
-            "No path was provided, ignoring"
+            "Segment contains a malformed percent-encoding"
         ));
     };
 
-    dist.reserve(128);
-    
-    match check_segment(first_segment) {
+    // an encoded slash would otherwise smuggle in another segment, which `check_segment` never sees on its own
+    if decoded.contains(&b'/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Segment contains an encoded slash"
+        ));
+    }
+
+    match check_segment(&decoded, policy) {
         // SAFETY: `check_segment` only allows valid utf8
-        Some(segment) => unsafe { dist.push(core::str::from_utf8_unchecked(segment)) }, 
+        Some(segment) => unsafe { dist.push(core::str::from_utf8_unchecked(segment)) },
         None => return Err(illegal!())
     }
+    Ok(())
+}
 
-    while let Some(segment) = path_iter.next() {
-        match check_segment(segment) {
-            // SAFETY: `check_segment` only allows valid utf8
-            Some(segment) => unsafe { dist.push(core::str::from_utf8_unchecked(segment) )},
-            None => return Err(illegal!())
+/// The form of a request path which it is routed by, so that the many spellings of a path (`a.js`, `a%2Ejs`, `%61.js`
+/// or `/a.js`) share a single route rather than each reading the file again. Segments are decoded, empty ones dropped
+/// and whatever may not appear in a segment as is encoded again, keeping the trailing slash of a directory.
+///
+/// Paths with a malformed percent-encoding are left as they are, they fail to route however they are spelled.
+#[must_use]
+pub fn normalize(path: &[u8]) -> Cow<'_, [u8]> {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let verbatim = |byte: u8| byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte);
+
+    if !path.contains(&b'%') && !path.starts_with(b"/") && !path.windows(2).any(|pair| pair == b"//") {
+        return Cow::Borrowed(path);
+    }
+
+    let mut normal = Vec::with_capacity(path.len());
+    for segment in path.split(|byte| *byte == b'/').filter(|segment| !segment.is_empty()) {
+        if !normal.is_empty() {
+            normal.push(b'/');
+        }
+        let Some(decoded) = percent_decode(segment) else { return Cow::Borrowed(path) };
+        for byte in decoded.iter().copied() {
+            if verbatim(byte) {
+                normal.push(byte);
+            } else {
+                normal.extend_from_slice(&[b'%', HEX[usize::from(byte >> 4)], HEX[usize::from(byte & 0xf)]]);
+            }
         }
     }
+    if path.ends_with(b"/") && !normal.is_empty() {
+        normal.push(b'/');
+    }
+    Cow::Owned(normal)
+}
+
+#[inline]
+pub fn extend_dist(mut dist: PathBuf, mut path_iter: PathIter, policy: &PathsConf) -> io::Result<PathBuf> {
+    let Some(first_segment) = path_iter.next() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No path was provided, ignoring"
+        ));
+    };
+
+    dist.reserve(128);
+
+    push_segment(&mut dist, first_segment, policy)?;
+    for segment in path_iter {
+        push_segment(&mut dist, segment, policy)?;
+    }
 
     Ok(dist)
 }
 
+/// Whether a decoded segment may name a file in the dist directory under `policy`. Whatever the policy, the segment may
+/// not be `.` or `..`, nor contain either path separator or a control character.
 #[inline(always)]
 #[must_use]
-fn check_segment(segment: &[u8]) -> Option<&[u8]> {
-    if segment.len() >= swift_check::arch::WIDTH {
-        if for_all_ensure(segment, any!(
-            range!(b'a'..=b'z'), range!(b'A'..=b'Z'), 
-            range!(b'0'..=b'9'), range!(b'-'..=b'.'), eq(b'_')
-        )) {
-            Some(segment)
+fn check_segment<'s>(segment: &'s [u8], policy: &PathsConf) -> Option<&'s [u8]> {
+    if !policy.deny.is_empty() && core::str::from_utf8(segment)
+        .is_ok_and(|segment| segment.contains(|c| policy.deny.contains(c)))
+    {
+        return None;
+    }
+
+    // the common case, only unreserved characters, may be checked a register at a time whatever the policy
+    if segment.len() >= swift_check::arch::WIDTH && for_all_ensure(segment, any!(
+        range!(b'a'..=b'z'), range!(b'A'..=b'Z'),
+        range!(b'0'..=b'9'), range!(b'-'..=b'.'), eq(b'_')
+    )) {
+        return Some(segment);
+    }
+
+    if matches!(segment, b".." | b".") {
+        return None
+    }
+
+    let allowed = match policy.charset {
+        Charset::Strict => segment.iter()
+            .all(|byte| matches!(byte, b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-'..=b'.')),
+        Charset::Ascii => segment.iter()
+            .all(|byte| matches!(byte, b' '..=b'~') && !matches!(byte, b'/' | b'\\')),
+        // `is_control` covers NUL, C0, DEL and C1
+        Charset::Utf8 => core::str::from_utf8(segment)
+            .is_ok_and(|segment| segment.chars().all(|c| !c.is_control() && !matches!(c, '/' | '\\')))
+    };
+    allowed.then_some(segment)
+}
+
+/// Where `path` may be opened from, resolved and checked by `confine` if there is one.
+#[inline]
+pub fn resolve(confine: Option<&Confine>, path: &Path) -> io::Result<PathBuf> {
+    confine.map_or_else(|| Ok(path.to_path_buf()), |confine| confine.check(path))
+}
+
+/// Keeps the files we serve within the dist directory once symlinks are resolved, see `paths.confine`.
+#[derive(Debug)]
+pub struct Confine {
+    root: PathBuf,
+    allow: &'static [PathBuf]
+}
+
+impl Confine {
+    /// `None` unless `paths.confine` is set.
+    #[must_use]
+    pub fn new(dist: &str, paths: &'static PathsConf) -> Option<Self> {
+        if !paths.confine {
+            return None;
+        }
+        let root = fs::canonicalize(dist).unwrap_or_else(|err| {
+            // nothing can be served from it for now anyway, though it may be created later
+            warn!("Could not resolve the dist directory `{dist}`: {err}");
+            PathBuf::from(dist)
+        });
+        Some(Self { root, allow: &paths.allow_symlinks })
+    }
+
+    /// Where `path` resolves to, ensuring it is within the dist directory or an allowed symlink target. Only the
+    /// resolved path may be opened, as a symlink may be swapped in along `path` once it was checked.
+    ///
+    /// Paths which do not exist pass as they are, they will be reported as missing when opened.
+    pub fn check(&self, path: &Path) -> io::Result<PathBuf> {
+        let resolved = match fs::canonicalize(path) {
+            Ok(resolved) => resolved,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(path.to_path_buf()),
+            Err(err) => return Err(err)
+        };
+
+        if resolved.starts_with(&self.root) || self.allow.iter().any(|dir| resolved.starts_with(dir)) {
+            Ok(resolved)
         } else {
-            None
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("`{}` resolves outside of the dist directory", path.display())
+            ))
         }
-    } else {
-        if matches!(segment, b".." | b".") {
-            return None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extend(path: &[u8]) -> io::Result<PathBuf> {
+        extend_dist(PathBuf::from("dist"), PathIter::new(path), &PathsConf::default())
+    }
+
+    fn allowed(segment: &str, charset: Charset, deny: &str) -> bool {
+        check_segment(segment.as_bytes(), &PathsConf { charset, deny: deny.to_owned(), ..PathsConf::default() }).is_some()
+    }
+
+    #[test]
+    fn decodes_segments() {
+        assert_eq!(extend(b"a/b.js").unwrap(), PathBuf::from("dist/a/b.js"));
+        assert_eq!(extend(b"my%20file.js").unwrap(), PathBuf::from("dist/my file.js"));
+        assert_eq!(extend(b"100%25.txt").unwrap(), PathBuf::from("dist/100%.txt"));
+        assert_eq!(extend(b"a-really-long-segment-name-for-the-fast-path.js").unwrap(),
+                   PathBuf::from("dist/a-really-long-segment-name-for-the-fast-path.js"));
+    }
+
+    #[test]
+    fn normalizes_spellings() {
+        let normal = |path: &'static [u8]| String::from_utf8(normalize(path).into_owned()).unwrap();
+
+        for path in [&b"a.js"[..], b"a%2Ejs", b"a%2ejs", b"%61.js", b"/a.js", b"//a.js"] {
+            assert_eq!(normal(path), "a.js", "{:?}", core::str::from_utf8(path));
         }
+        assert_eq!(normal(b"docs//guide/"), "docs/guide/");
+        assert_eq!(normal(b"my%20file%2fx%c3%a9.js"), "my%20file%2Fx%C3%A9.js");
+        assert_eq!(normal(b"100%25.txt"), "100%25.txt");
+        assert_eq!(normal(b"/a%2"), "/a%2");
 
-        let mut pos = 0usize;
-        loop {
-            match segment.get(pos) {
-                Some(seg) if matches!(seg, b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-'..=b'.') => {
-                    pos += 1;
-                },
-                Some(_seg) => break None,
-                None => break Some(segment)
-            }
+        // encoded again, so it decodes to the same file
+        let path = normal(b"my%20fil%65.js");
+        assert_eq!(extend(path.as_bytes()).unwrap(), PathBuf::from("dist/my file.js"));
+    }
+
+    #[test]
+    fn rejects_segments() {
+        for path in [
+            &b"a%2Fb.js"[..], b"a%2fb.js", b"..", b"a/%2E%2E/b.js", b"%2e", b"a%5Cb.js", b"a%00.js", b"a%zz.js", b"a%2",
+            b"a%0A.js"
+        ] {
+            assert!(extend(path).is_err(), "{:?} was accepted", core::str::from_utf8(path));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confined_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let base = std::env::temp_dir().join(format!("test-site-confine-{}", std::process::id()));
+        let (dist, outside) = (base.join("dist"), base.join("outside"));
+        fs::create_dir_all(dist.join("nested")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(dist.join("nested/inner.js"), "inner").unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        symlink(dist.join("nested/inner.js"), dist.join("inner.js")).unwrap();
+        symlink(outside.join("secret.txt"), dist.join("secret.txt")).unwrap();
+        symlink(&outside, dist.join("build")).unwrap();
+
+        let paths: &'static PathsConf = Box::leak(Box::new(PathsConf { confine: true, ..PathsConf::default() }));
+        let confine = Confine::new(dist.to_str().unwrap(), paths).unwrap();
+        let root = fs::canonicalize(&dist).unwrap();
+        assert_eq!(confine.check(&dist.join("inner.js")).unwrap(), root.join("nested/inner.js"));
+        assert_eq!(confine.check(&dist.join("missing.js")).unwrap(), dist.join("missing.js"));
+        for escaped in ["secret.txt", "build/secret.txt"] {
+            let err = confine.check(&dist.join(escaped)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{escaped}");
         }
+
+        let allowed: &'static PathsConf = Box::leak(Box::new(PathsConf {
+            confine: true,
+            allow_symlinks: vec![fs::canonicalize(&outside).unwrap()],
+            ..PathsConf::default()
+        }));
+        let confine = Confine::new(dist.to_str().unwrap(), allowed).unwrap();
+        assert_eq!(
+            confine.check(&dist.join("build/secret.txt")).unwrap(),
+            fs::canonicalize(outside.join("secret.txt")).unwrap()
+        );
+
+        assert!(Confine::new(dist.to_str().unwrap(), Box::leak(Box::default())).is_none());
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn charsets() {
+        use Charset::*;
+
+        for segment in ["chunk@2x.png", "a+b~c.js", "my file.txt"] {
+            assert!(!allowed(segment, Strict, ""), "{segment}");
+            assert!(allowed(segment, Ascii, ""), "{segment}");
+            assert!(allowed(segment, Utf8, ""), "{segment}");
+        }
+        for segment in ["\u{65e5}\u{672c}\u{8a9e}.json", "caf\u{e9}-menu-with-a-long-enough-name.css"] {
+            assert!(!allowed(segment, Ascii, ""), "{segment}");
+            assert!(allowed(segment, Utf8, ""), "{segment}");
+        }
+        for segment in ["a\u{0}b", "a\tb", "a\u{7f}", "a\u{85}b", "a\\b", "..", "."] {
+            assert!(!allowed(segment, Utf8, ""), "{segment:?}");
+        }
+        assert!(check_segment(b"caf\xe9.css", &PathsConf::default()).is_none());
+
+        assert!(allowed("bundle-0123456789abcdef.min.js", Strict, ""));
+        assert!(!allowed("bundle-0123456789abcdef.min.js", Utf8, "-"));
+        assert!(!allowed("a<b>.js", Ascii, "<>"));
     }
 }
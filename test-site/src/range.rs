@@ -0,0 +1,277 @@
+//! Byte-range requests, see RFC 9110 section 14
+use crate::conditional::{parse_date, secs};
+use crate::lazy_file::Contents;
+use crate::parse::Headers;
+use crate::response::{Response, Status, Body};
+use core::fmt::Write as _;
+use core::ops::Range;
+
+/// More ranges than this in a single request are treated as abuse, the full file is served instead.
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// There is no (usable) `Range` header, the full file should be served
+    Full,
+    /// The satisfiable ranges in ascending order, overlapping or adjacent ones merged and unsatisfiable ones dropped
+    Satisfiable(Vec<Range<usize>>),
+    /// Not a single range overlaps the file
+    Unsatisfiable
+}
+
+/// Parse a non-empty run of digits, saturating as a huge last position simply means "until the end".
+#[inline]
+#[must_use]
+fn parse_pos(raw: &[u8]) -> Option<usize> {
+    if raw.is_empty() || !raw.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(raw.iter().fold(0usize, |acc, digit| acc.saturating_mul(10).saturating_add(usize::from(digit - b'0'))))
+}
+
+/// Parse a single range spec against a file of `len` bytes.
+///
+/// `None` if the spec is invalid, which invalidates the entire header, `Some(None)` if it is valid but does not overlap
+/// the file.
+#[must_use]
+fn parse_spec(spec: &[u8], len: usize) -> Option<Option<Range<usize>>> {
+    let dash = spec.iter().position(|byte| *byte == b'-')?;
+    let (first, last) = (&spec[..dash], &spec[dash + 1..]);
+
+    if first.is_empty() {
+        // a suffix range, the final `n` bytes
+        return match parse_pos(last)? {
+            0 => Some(None),
+            _ if len == 0 => Some(None),
+            suffix => Some(Some(len.saturating_sub(suffix)..len))
+        };
+    }
+
+    let start = parse_pos(first)?;
+    let end = match last {
+        b"" => len,
+        last => {
+            let last = parse_pos(last)?;
+            if last < start {
+                return None;
+            }
+            core::cmp::min(last.saturating_add(1), len)
+        }
+    };
+
+    Some((start < len).then_some(start..end))
+}
+
+/// Parse the value of a `Range` header against a file of `len` bytes.
+#[must_use]
+pub fn parse(header: &[u8], len: usize) -> Ranges {
+    let header = header.trim_ascii();
+    let Some(specs) = header.get(..6)
+        .filter(|unit| unit.eq_ignore_ascii_case(b"bytes="))
+        .map(|_| &header[6..])
+    else {
+        return Ranges::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut seen = 0usize;
+
+    for spec in specs.split(|byte| *byte == b',').map(<[u8]>::trim_ascii).filter(|spec| !spec.is_empty()) {
+        seen += 1;
+        if seen > MAX_RANGES {
+            return Ranges::Full;
+        }
+        match parse_spec(spec, len) {
+            Some(Some(range)) => ranges.push(range),
+            Some(None) => (),
+            None => return Ranges::Full
+        }
+    }
+
+    match (seen, ranges.is_empty()) {
+        (0, _) => return Ranges::Full,
+        (_, true) => return Ranges::Unsatisfiable,
+        (_, false) => ()
+    }
+
+    // asking for more than the whole file means asking for some of it repeatedly, which we decline rather than build a
+    // body many times the size of the file (RFC 9110 section 14.2 leaves such requests to the server)
+    if ranges.iter().map(Range::len).fold(0usize, usize::saturating_add) > len {
+        return Ranges::Full;
+    }
+    ranges.sort_unstable_by_key(|range| range.start);
+    ranges.dedup_by(|next, prev| {
+        let merges = next.start <= prev.end;
+        if merges {
+            prev.end = core::cmp::max(prev.end, next.end);
+        }
+        merges
+    });
+    Ranges::Satisfiable(ranges)
+}
+
+/// Whether the validator of an `If-Range` header still matches `contents`, only then may a partial response be sent.
+///
+/// Entity tags must match with the strong comparison, dates must be exactly the modification time.
+#[must_use]
+fn if_range_matches(if_range: &[u8], contents: &Contents) -> bool {
+    let if_range = if_range.trim_ascii();
+    if if_range.starts_with(b"\"") {
+        return if_range == contents.etag().as_bytes();
+    }
+    match (parse_date(if_range), contents.modified()) {
+        (Some(date), Some(modified)) => secs(date) == secs(modified),
+        _ => false
+    }
+}
+
+/// The ranges of `contents` requested by a `GET` request.
+#[must_use]
+pub fn requested(headers: &Headers, contents: &Contents) -> Ranges {
+    let Some(range) = headers.get("range") else { return Ranges::Full };
+
+    if headers.get("if-range").is_some_and(|if_range| !if_range_matches(if_range, contents)) {
+        // the client's partial copy is outdated, so it needs the full file
+        return Ranges::Full;
+    }
+
+    parse(range, contents.bytes().len())
+}
+
+#[inline]
+#[must_use]
+fn content_range(range: &Range<usize>, len: usize) -> String {
+    format!("bytes {}-{}/{len}", range.start, range.end - 1)
+}
+
+/// Respond with the requested `ranges` of `contents`.
+///
+/// A single range is served as is, sharing the bytes of the file. Multiple ranges are copied into a
+/// `multipart/byteranges` body, each part carrying its own `Content-Type` and `Content-Range`.
+pub fn response(ranges: Ranges, contents: &Contents, mime: &'static str) -> Response {
+    let bytes = contents.bytes();
+    let len = bytes.len();
+
+    match ranges {
+        Ranges::Full => Response::new(Status::OK, Body::Shared(bytes.clone()))
+            .header("Content-Type", mime),
+        Ranges::Unsatisfiable => Response::status(Status::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", format!("bytes */{len}")),
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = ranges.into_iter().next().unwrap(/* infallible, there is exactly one */);
+            Response::new(Status::PARTIAL_CONTENT, Body::Slice(bytes.clone(), range.clone()))
+                .header("Content-Type", mime)
+                .header("Content-Range", content_range(&range, len))
+        },
+        Ranges::Satisfiable(ranges) => {
+            // the boundary must not occur within the parts, deriving it from the hash of the contents makes that
+            // vanishingly unlikely.
+            let boundary = format!("test-site-{}", contents.etag().trim_matches('"'));
+
+            let mut body = Vec::with_capacity(ranges.iter().map(|range| range.len() + 128).sum());
+            let mut part = String::with_capacity(128);
+            for range in &ranges {
+                part.clear();
+                // writing to a `String` is infallible
+                let _ = write!(
+                    part,
+                    "--{boundary}\r\nContent-Type: {mime}\r\nContent-Range: {}\r\n\r\n",
+                    content_range(range, len)
+                );
+                body.extend_from_slice(part.as_bytes());
+                body.extend_from_slice(&bytes[range.clone()]);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+            Response::new(Status::PARTIAL_CONTENT, Body::Shared(body.into()))
+                .header("Content-Type", format!("multipart/byteranges; boundary={boundary}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! satisfiable {
+        ($($range:expr),* $(,)?) => {
+            Ranges::Satisfiable(vec![$($range),*])
+        };
+    }
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(parse(b"bytes=0-4", 10), satisfiable![0..5]);
+        assert_eq!(parse(b"Bytes=5-", 10), satisfiable![5..10]);
+        assert_eq!(parse(b"bytes=-3", 10), satisfiable![7..10]);
+        assert_eq!(parse(b"bytes=-30", 10), satisfiable![0..10]);
+        assert_eq!(parse(b"bytes=8-99999999999999999999999", 10), satisfiable![8..10]);
+        assert_eq!(parse(b"bytes= 0-1 , 4-5,, 20-30", 10), satisfiable![0..2, 4..6]);
+
+        assert_eq!(parse(b"bytes=10-", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse(b"bytes=-0", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse(b"bytes=-5", 0), Ranges::Unsatisfiable);
+
+        // invalid headers are ignored
+        assert_eq!(parse(b"bytes=5-4", 10), Ranges::Full);
+        assert_eq!(parse(b"bytes=a-4", 10), Ranges::Full);
+        assert_eq!(parse(b"bytes=+1-4", 10), Ranges::Full);
+        assert_eq!(parse(b"bytes=", 10), Ranges::Full);
+        assert_eq!(parse(b"lines=0-4", 10), Ranges::Full);
+        assert_eq!(parse(b"bytes=0-0,".repeat(MAX_RANGES + 1).as_slice(), 10), Ranges::Full);
+    }
+
+    #[test]
+    fn merges_overlaps() {
+        assert_eq!(parse(b"bytes=0-10,5-20", 100), satisfiable![0..21]);
+        assert_eq!(parse(b"bytes=50-59,0-9,10-19", 100), satisfiable![0..20, 50..60]);
+        assert_eq!(parse(b"bytes=-10,80-89", 100), satisfiable![80..100]);
+
+        // more than the file in total
+        assert_eq!(parse(b"bytes=0-,0-", 100), Ranges::Full);
+        assert_eq!(parse(b"bytes=0-10,5-20", 15), Ranges::Full);
+        assert_eq!(parse(b"bytes=0-,".repeat(MAX_RANGES).as_slice(), 100), Ranges::Full);
+    }
+
+    #[test]
+    fn if_range() {
+        let contents = Contents::new(b"0123456789".to_vec(), Some(std::time::UNIX_EPOCH));
+        let mut headers = Headers::new();
+        headers.push(b"Range", b"bytes=0-4");
+        assert_eq!(requested(&headers, &contents), satisfiable![0..5]);
+
+        let etag = contents.etag().to_owned();
+        let mut matching = headers.clone();
+        matching.push(b"If-Range", etag.as_bytes());
+        assert_eq!(requested(&matching, &contents), satisfiable![0..5]);
+
+        let weak = format!("W/{etag}");
+        let mut weak_headers = headers.clone();
+        weak_headers.push(b"If-Range", weak.as_bytes());
+        assert_eq!(requested(&weak_headers, &contents), Ranges::Full);
+
+        let mut dated = headers.clone();
+        dated.push(b"If-Range", b"Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(requested(&dated, &contents), satisfiable![0..5]);
+        headers.push(b"If-Range", b"Thu, 01 Jan 1970 00:00:01 GMT");
+        assert_eq!(requested(&headers, &contents), Ranges::Full);
+    }
+
+    #[test]
+    fn multipart() {
+        let contents = Contents::new(b"0123456789".to_vec(), None);
+        let res = response(satisfiable![0..2, 8..10], &contents, "text/plain");
+        let boundary = format!("test-site-{}", contents.etag().trim_matches('"'));
+
+        assert_eq!(res.status, Status::PARTIAL_CONTENT);
+        assert_eq!(
+            res.body.as_bytes(),
+            format!(
+                "--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+                 --{boundary}--\r\n"
+            ).as_bytes()
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Responses, independent of the connection they are written to
+use std::borrow::Cow;
+use std::io::{self, IoSlice};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use core::fmt::Write as _;
+use core::ops::Range;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Status {
+    code: u16,
+    reason: &'static str
+}
+
+macro_rules! statuses {
+    ($($name:ident = $code:literal $reason:literal),* $(,)?) => {
+        impl Status {
+            $(pub const $name: Self = Self { code: $code, reason: $reason };)*
+
+            /// The status with this code, if it is one we respond with
+            #[must_use]
+            pub const fn from_code(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(Self::$name),)*
+                    _ => None
+                }
+            }
+        }
+    };
+}
+
+statuses! {
+    OK = 200 "OK",
+    NO_CONTENT = 204 "No Content",
+    PARTIAL_CONTENT = 206 "Partial Content",
+    MOVED_PERMANENTLY = 301 "Moved Permanently",
+    NOT_MODIFIED = 304 "Not Modified",
+    TEMPORARY_REDIRECT = 307 "Temporary Redirect",
+    BAD_REQUEST = 400 "Bad Request",
+    FORBIDDEN = 403 "Forbidden",
+    NOT_FOUND = 404 "Not Found",
+    METHOD_NOT_ALLOWED = 405 "Method Not Allowed",
+    REQUEST_TIMEOUT = 408 "Request Timeout",
+    PAYLOAD_TOO_LARGE = 413 "Content Too Large",
+    URI_TOO_LONG = 414 "URI Too Long",
+    RANGE_NOT_SATISFIABLE = 416 "Range Not Satisfiable",
+    HEADER_TOO_LARGE = 431 "Request Header Fields Too Large",
+    INTERNAL_SERVER_ERROR = 500 "Internal Server Error",
+    SERVICE_UNAVAILABLE = 503 "Service Unavailable",
+    VERSION_NOT_SUPPORTED = 505 "HTTP Version Not Supported",
+}
+
+impl Status {
+    #[inline]
+    #[must_use]
+    pub const fn code(&self) -> u16 {
+        self.code
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn reason(&self) -> &'static str {
+        self.reason
+    }
+
+    /// Whether the status reports a client (`4xx`) or server (`5xx`) error
+    #[inline]
+    #[must_use]
+    pub const fn is_error(&self) -> bool {
+        self.code >= 400
+    }
+
+    /// Whether responses with this status are never allowed to carry a body (or `Content-Length`)
+    #[inline]
+    #[must_use]
+    pub const fn is_bodiless(&self) -> bool {
+        matches!(self.code, 100..=199 | 204 | 304)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Body {
+    Empty,
+    Static(&'static [u8]),
+    Shared(Arc<[u8]>),
+    /// Part of shared bytes, such as a single range of a file
+    Slice(Arc<[u8]>, Range<usize>),
+    /// Server-sent events, streamed until the client goes away
+    #[cfg(feature = "reload")]
+    Events(&'static crate::live::Events)
+}
+
+impl Body {
+    #[inline]
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Empty => &[],
+            Self::Static(bytes) => bytes,
+            Self::Shared(bytes) => bytes,
+            Self::Slice(bytes, range) => &bytes[range.clone()],
+            #[cfg(feature = "reload")]
+            Self::Events(_) => &[]
+        }
+    }
+
+    /// Whether the body is streamed rather than known upfront, so it has no length and ends the connection
+    #[inline]
+    #[must_use]
+    pub const fn is_streamed(&self) -> bool {
+        #[cfg(feature = "reload")] {
+            matches!(self, Self::Events(_))
+        }
+        #[cfg(not(feature = "reload"))] {
+            false
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+}
+
+pub type HeaderValue = Cow<'static, str>;
+
+#[derive(Debug)]
+#[must_use]
+pub struct Response {
+    pub status: Status,
+    pub headers: Vec<(&'static str, HeaderValue)>,
+    pub body: Body
+}
+
+impl Response {
+    #[inline]
+    pub fn new(status: Status, body: Body) -> Self {
+        Self { status, headers: Vec::with_capacity(4), body }
+    }
+
+    /// A plain text response whose body is the reason phrase of `status`
+    #[inline]
+    pub fn status(status: Status) -> Self {
+        Self::new(status, Body::Static(status.reason.as_bytes()))
+            .header("Content-Type", "text/plain")
+    }
+
+    #[inline]
+    pub fn header(mut self, name: &'static str, value: impl Into<HeaderValue>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Like [`header`](Self::header), though replacing any headers of the same name
+    #[inline]
+    pub fn set_header(mut self, name: &'static str, value: impl Into<HeaderValue>) -> Self {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.header(name, value)
+    }
+
+    /// Serialize the response as HTTP/1.1, framed with `Content-Length` so that the connection may be reused.
+    ///
+    /// If `head_only` the body is omitted, though `Content-Length` still reflects it as required for `HEAD` requests.
+    /// Streamed bodies are framed by closing the connection instead, so `keep_alive` must not be set for them, and only
+    /// their head is written here, the connection streams the rest.
+    pub async fn write_h1<S>(&self, stream: &mut S, keep_alive: bool, head_only: bool) -> io::Result<()>
+        where S: AsyncWrite + Unpin
+    {
+        let mut head = String::with_capacity(128);
+
+        // writing to a `String` is infallible
+        let _ = write!(head, "HTTP/1.1 {} {}\r\n", self.status.code, self.status.reason);
+        for (name, value) in &self.headers {
+            let _ = write!(head, "{name}: {value}\r\n");
+        }
+        if !self.status.is_bodiless() && !self.body.is_streamed() {
+            let _ = write!(head, "Content-Length: {}\r\n", self.body.len());
+        }
+        let _ = write!(head, "Connection: {}\r\n\r\n", if keep_alive { "keep-alive" } else { "close" });
+
+        let body = if head_only || self.status.is_bodiless() { &[] } else { self.body.as_bytes() };
+        write_all_vectored(stream, &mut [
+            IoSlice::new(head.as_bytes()),
+            IoSlice::new(body)
+        ]).await?;
+
+        stream.flush().await
+    }
+}
+
+/// `write_vectored` until every slice has been written
+async fn write_all_vectored<S>(stream: &mut S, mut slices: &mut [IoSlice<'_>]) -> io::Result<()>
+    where S: AsyncWrite + Unpin
+{
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match stream.write_vectored(slices).await? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            written => IoSlice::advance_slices(&mut slices, written)
+        }
+    }
+    Ok(())
+}
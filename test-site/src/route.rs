@@ -1,8 +1,13 @@
 use bumpalo::{Bump, collections::Vec};
-use core::cell::UnsafeCell;
 use crate::parse::PathIter;
-use core::ops::Deref;
+use std::sync::{Arc, RwLock, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
 use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ptr::NonNull;
+
+/// The arena of a [`SyncTree`] is only compacted once it has grown past this many bytes
+const COMPACT_ABOVE: usize = 64 * 1024;
 
 #[derive(Debug)]
 pub struct Tree<'b, T> {
@@ -14,7 +19,9 @@ pub struct Node<'b, T> {
     prefix: Vec<'b, u8>,
     priority: u32,
     children: Vec<'b, Self>,
-    value: Option<T>
+    // values are reference counted as the node (and therefore the value) may be moved when a sibling is inserted, this
+    // also allows handlers to continue using a value while the tree is being modified.
+    value: Option<Arc<T>>
 }
 
 impl<'b, T: fmt::Debug> fmt::Debug for Node<'b, T> {
@@ -28,38 +35,11 @@ impl<'b, T: fmt::Debug> fmt::Debug for Node<'b, T> {
     }
 }
 
-enum Acquired<'b, 'r, T> {
-    Root(&'b mut Node<'b, T>, PathIter<'r>),
-    Exact(&'b mut Node<'b, T>),
-    SplitClosest(&'b mut Node<'b, T>, usize, PathIter<'r>),
-    CreateClosest(&'b mut Node<'b, T>, PathIter<'r>) 
-}
-
-#[repr(transparent)]
-#[must_use]
-struct Cur<'b, T> {
-    cell: UnsafeCell<&'b mut Node<'b, T>>
-}
-
-impl<'b, T> Cur<'b, T> {
-    #[inline(always)]
-    pub fn new(t: &'b mut Node<'b, T>) -> Self {
-        Self { cell: UnsafeCell::new(t) }
-    }
-
-    #[inline(always)]
-    pub fn get(&self) -> &'b mut Node<'b, T> {
-        unsafe { *self.cell.get() }
-    }
-}
-
-impl<'b, T> Deref for Cur<'b, T> {
-    type Target = Node<'b, T>;
-
-    #[inline]
-    fn deref(&self) -> &Self::Target {
-        self.get()
-    }
+enum Acquired<'n, 'b, 'r, T> {
+    Root(&'n mut Node<'b, T>, PathIter<'r>),
+    Exact(&'n mut Node<'b, T>),
+    SplitClosest(&'n mut Node<'b, T>, usize, PathIter<'r>),
+    CreateClosest(&'n mut Node<'b, T>, PathIter<'r>)
 }
 
 impl<'b, T> Node<'b, T> {
@@ -74,6 +54,17 @@ impl<'b, T> Node<'b, T> {
         }
     }
 
+    #[inline]
+    #[must_use]
+    fn leaf(arena: &'b Bump, prefix: &[u8]) -> Self {
+        Self {
+            prefix: Vec::from_iter_in(prefix.iter().copied(), arena),
+            priority: 1,
+            children: Vec::new_in(arena),
+            value: None
+        }
+    }
+
     #[inline]
     #[must_use]
     pub fn prefix(&self) -> &[u8] {
@@ -81,34 +72,127 @@ impl<'b, T> Node<'b, T> {
     }
 
     #[inline(always)]
-    fn acquire<'r>(&'b mut self, route: &mut PathIter<'r>) -> Acquired<'b, 'r, T> {
+    fn child_for(&self, route: &PathIter) -> Option<usize> {
+        let next = route.peek()?;
+        self.children.iter().position(|child| child.prefix.first() == Some(&next))
+    }
+
+    #[inline(always)]
+    fn acquire<'r>(&mut self, route: &mut PathIter<'r>) -> Acquired<'_, 'b, 'r, T> {
         if self.prefix.is_empty() && self.children.is_empty() {
             return Acquired::Root(self, *route);
         }
 
-        let mut current = Cur::new(self);
+        let mut current = self;
 
         loop {
-            let matched = route.move_match(current.prefix());
+            let matched = route.match_prefix(current.prefix());
+
+            if matched < current.prefix.len() {
+                current.priority += 1;
+                return Acquired::SplitClosest(current, matched, *route);
+            }
+
             if route.peek_complete() {
-                return Acquired::Exact(current.get());
+                return Acquired::Exact(current);
             }
 
-            if matched.len() < current.prefix.len() {
-                current.get().priority += 1;
-                return Acquired::SplitClosest(current.get(), matched.len(), *route);
+            match current.child_for(route) {
+                Some(child) => current = &mut current.children[child],
+                None => {
+                    current.priority += 1;
+                    return Acquired::CreateClosest(current, *route);
+                }
+            }
+        }
+    }
+
+    /// Whether the node neither holds a value nor leads to one
+    #[inline]
+    fn is_vacant(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    /// Absorb the only child of a node without a value, as nothing is routed to where they were split any more.
+    fn merge(&mut self) {
+        if self.value.is_some() || self.children.len() != 1 {
+            return;
+        }
+        let Node { prefix, priority, children, value } = self.children.pop().unwrap(/* infallible, there is one */);
+        self.prefix.extend_from_slice(&prefix);
+        self.priority = priority;
+        self.children = children;
+        self.value = value;
+    }
+
+    /// Take the value at `route` below this node, dropping or merging the nodes which no longer need to be split.
+    fn remove(&mut self, route: &mut PathIter) -> Option<Arc<T>> {
+        if route.match_prefix(self.prefix()) < self.prefix.len() {
+            return None;
+        }
+        if route.peek_complete() {
+            return self.value.take();
+        }
+
+        let i = self.child_for(route)?;
+        let removed = self.children[i].remove(route)?;
+        if self.children[i].is_vacant() {
+            // siblings are told apart by their first byte, so their order is of no concern
+            self.children.swap_remove(i);
+        } else {
+            self.children[i].merge();
+        }
+        Some(removed)
+    }
+
+    /// Drop the values of this node and its descendants for which `keep` does not hold, along with the nodes which no
+    /// longer lead to a value. Returns how many values were dropped.
+    fn retain<F: FnMut(&T) -> bool>(&mut self, keep: &mut F) -> usize {
+        let mut dropped = 0;
+        if self.value.as_deref().is_some_and(|value| !keep(value)) {
+            self.value = None;
+            dropped += 1;
+        }
+        for child in self.children.iter_mut() {
+            dropped += child.retain(keep);
+            child.merge();
+        }
+        self.children.retain(|child| !child.is_vacant());
+        dropped
+    }
+
+    /// A copy of this node and its descendants allocated in `arena`, each vector only as large as it needs to be.
+    fn copy_into<'c>(&self, arena: &'c Bump) -> Node<'c, T> {
+        let mut children = Vec::with_capacity_in(self.children.len(), arena);
+        children.extend(self.children.iter().map(|child| child.copy_into(arena)));
+        Node {
+            prefix: Vec::from_iter_in(self.prefix.iter().copied(), arena),
+            priority: self.priority,
+            children,
+            value: self.value.clone()
+        }
+    }
+
+    /// The read-only counterpart of `acquire`, yielding the node which exactly matches `route` if any.
+    #[inline(always)]
+    fn find(&self, route: &mut PathIter) -> Option<&Self> {
+        let mut current = self;
+
+        loop {
+            if route.match_prefix(current.prefix()) < current.prefix.len() {
+                return None;
             }
 
-            if let Some(child) = current.get().children.iter_mut().find(|c| route.next_is_opt(c.prefix.first())) {
-                current = Cur::new(child);
-            } else {
-                current.get().priority += 1;
-                return Acquired::CreateClosest(current.get(), *route);
+            if route.peek_complete() {
+                return Some(current);
             }
+
+            current = &current.children[current.child_for(route)?];
         }
     }
 }
 
+#[cfg(test)]
 impl<T> Tree<'static, T> {
     /// Create a new [`Tree`] with a `static` lifetime (intentionally leaked memory)
     #[must_use]
@@ -128,108 +212,540 @@ impl<'b, T> Tree<'b, T> {
         }
     }
 
-    pub fn get_or_try_create<'r, F, E>(&'b mut self, mut path: PathIter<'r>, f: F) -> Result<&'b T, E>
+    #[inline]
+    #[must_use]
+    pub fn get(&self, mut path: PathIter) -> Option<&Arc<T>> {
+        self.root.find(&mut path).and_then(|node| node.value.as_ref())
+    }
+
+    /// The value of the deepest node along `path` which ends on a segment boundary, advancing `path` past it. This is
+    /// how prefixes are resolved, a value at `app` matches `app` and `app/x.js` though not `application`.
+    #[must_use]
+    pub fn longest_match(&self, path: &mut PathIter) -> Option<&Arc<T>> {
+        let mut current = &self.root;
+        let mut probe = *path;
+        let mut best = None;
+
+        loop {
+            if probe.match_prefix(current.prefix()) < current.prefix.len() {
+                break;
+            }
+            if let Some(value) = &current.value {
+                if matches!(probe.peek(), Some(b'/' | b' ') | None) {
+                    best = Some((value, probe));
+                }
+            }
+            if probe.peek_complete() {
+                break;
+            }
+            match current.child_for(&probe) {
+                Some(child) => current = &current.children[child],
+                None => break
+            }
+        }
+
+        best.map(|(value, rest)| {
+            *path = rest;
+            value
+        })
+    }
+
+    /// Set the value at `path`, returning the value it replaced if there was one.
+    pub fn replace(&mut self, mut path: PathIter, value: Arc<T>) -> Option<Arc<T>> {
+        let arena = self.arena;
+        let node = match self.root.acquire(&mut path) {
+            Acquired::Exact(node) => node,
+            Acquired::Root(node, rem_path) => {
+                node.prefix = Vec::from_iter_in(rem_path.rest().iter().copied(), arena);
+                node
+            },
+            Acquired::SplitClosest(node, common, rem_path) => Self::split_node(arena, node, common, rem_path),
+            Acquired::CreateClosest(node, rem_path) => Self::create_child(arena, node, rem_path)
+        };
+        node.value.replace(value)
+    }
+
+    /// Take the value at `path`, so that it is created afresh when next requested. Nodes which no longer lead to a
+    /// value are dropped, and those left with a single child are merged with it.
+    pub fn remove(&mut self, mut path: PathIter) -> Option<Arc<T>> {
+        let removed = self.root.remove(&mut path)?;
+        self.tidy_root();
+        Some(removed)
+    }
+
+    /// Keep only the values for which `keep` holds, tidying the nodes like [`remove`](Self::remove). Returns how many
+    /// values were dropped.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) -> usize {
+        let dropped = self.root.retain(&mut keep);
+        self.tidy_root();
+        dropped
+    }
+
+    /// A copy of the tree allocated in `arena`, leaving behind the memory of the nodes which were grown, split and
+    /// removed along the way.
+    #[must_use]
+    pub fn compact_into<'c>(&self, arena: &'c Bump) -> Tree<'c, T> {
+        Tree { root: self.root.copy_into(arena), arena }
+    }
+
+    #[inline]
+    fn tidy_root(&mut self) {
+        if self.root.is_vacant() {
+            // back to an empty tree, which the next insertion takes over
+            self.root.prefix.clear();
+        } else {
+            self.root.merge();
+        }
+    }
+
+    pub fn get_or_try_create<'r, F, E>(&mut self, mut path: PathIter<'r>, f: F) -> Result<&Arc<T>, E>
         where F: FnOnce(PathIter<'r>) -> Result<T, E>
     {
-        let m_self = UnsafeCell::new(self);
-        match unsafe { &mut *m_self.get() }.root.acquire(&mut path) {
+        let arena = self.arena;
+        let node = match self.root.acquire(&mut path) {
             Acquired::Exact(node) => {
                 if node.value.is_none() {
-                    node.value = Some(f(path)?);
-                }
-                match &mut node.value {
-                    Some(value) => Ok(value),
-                    // SAFETY: If the node was `None` we assigned the `Ok` of `f` to the value. If `f` failed we did 
-                    // not make it to this point. Thus at this point `node.value` cannot be `None`.
-                    None => unsafe { core::hint::unreachable_unchecked() }
+                    node.value = Some(Arc::new(f(path)?));
                 }
+                node
             },
-            Acquired::Root(mut node, mut rem_path) => match f(rem_path) {
-                Ok(new_value) => {
-                    let arena = unsafe { & *m_self.get() }.arena;
-                    let priority = node.priority;
-
-                    if let Some(segment) = rem_path.next() {
-                        node.prefix = Vec::from_iter_in(segment.iter().copied(), arena);
-                    }
-
-                    while let Some(segment) = rem_path.next() {
-                        node.children.push(Node {
-                            priority,
-                            prefix: Vec::from_iter_in(segment.iter().copied(), arena),
-                            children: Vec::new_in(arena),
-                            value: None
-                        });
-
-                        node = node.children.last_mut().unwrap();
-                    }
-
-                    node.value = Some(new_value);
-                    Ok(node.value.as_mut().unwrap())
-                },
-                Err(err) => Err(err)
+            Acquired::Root(node, rem_path) => {
+                let new_value = f(rem_path)?;
+                node.prefix = Vec::from_iter_in(rem_path.rest().iter().copied(), arena);
+                node.value = Some(Arc::new(new_value));
+                node
             },
-            Acquired::SplitClosest(node, common, rem_path) => match f(rem_path) {
-                Ok(new_value) => {
-                    let split = unsafe { & *m_self.get() }.split_node(node, common, rem_path);
-                    split.value = Some(new_value);
-                    Ok(split.value.as_mut().unwrap())
-                },
-                Err(err) => Err(err)
+            Acquired::SplitClosest(node, common, rem_path) => {
+                let new_value = f(rem_path)?;
+                let split = Self::split_node(arena, node, common, rem_path);
+                split.value = Some(Arc::new(new_value));
+                split
             },
-            Acquired::CreateClosest(node, rem_path) => match f(rem_path) {
-                Ok(new_value) => {
-                    let new = unsafe { & *m_self.get() }.create_child(node, rem_path);
-                    new.value = Some(new_value);
-                    Ok(new.value.as_mut().unwrap())
-                },
-                Err(err) => Err(err) 
+            Acquired::CreateClosest(node, rem_path) => {
+                let new_value = f(rem_path)?;
+                let new = Self::create_child(arena, node, rem_path);
+                new.value = Some(Arc::new(new_value));
+                new
             }
+        };
+
+        match &node.value {
+            Some(value) => Ok(value),
+            // SAFETY: Every branch above either assigned `Some` to the value or returned early with the error of `f`.
+            None => unsafe { core::hint::unreachable_unchecked() }
         }
     }
 
-    fn split_node<'r>(&'b self, node: &'b mut Node<'b, T>, common: usize, mut rest: PathIter<'r>) -> &'b mut Node<'b, T> {
+    fn split_node<'n>(
+        arena: &'b Bump, node: &'n mut Node<'b, T>, common: usize, rest: PathIter
+    ) -> &'n mut Node<'b, T> {
         if common < node.prefix.len() {
             // Split the current node
             let new_child = Node {
-                prefix: Vec::from_iter_in(node.prefix[common..].iter().copied(), self.arena),
+                prefix: Vec::from_iter_in(node.prefix[common..].iter().copied(), arena),
                 priority: node.priority,
-                children: core::mem::replace(&mut node.children, Vec::new_in(self.arena)),
+                children: core::mem::replace(&mut node.children, Vec::new_in(arena)),
                 value: node.value.take(),
             };
             node.prefix.truncate(common);
             node.children.push(new_child);
         }
 
-        if let Some(next_segment) = rest.next() {
-            // Create a new child for the diverging part
-            let new_child = Node {
-                prefix: Vec::from_iter_in(next_segment.iter().copied(), self.arena),
-                priority: 1,
-                children: Vec::new_in(self.arena),
-                value: None,
-            };
-            node.children.push(new_child);
-            self.create_child(node.children.last_mut().unwrap(), rest)
-        } else {
+        if rest.peek_complete() {
             node
+        } else {
+            // Create a new child for the diverging part
+            Self::create_child(arena, node, rest)
         }
     }
 
-    fn create_child<'r>(&'b self, parent: &'b mut Node<'b, T>, rest_path: PathIter<'r>) -> &'b mut Node<'b, T> {
-        let mut current = parent;
-        for segment in rest_path {
-            let new_node = Node {
-                prefix: Vec::from_iter_in(segment.iter().copied(), self.arena),
-                priority: 1,
-                children: Vec::new_in(self.arena),
-                value: None,
-            };
-            current.children.push(new_node);
-            current = current.children.last_mut().unwrap();
+    #[inline]
+    fn create_child<'n>(arena: &'b Bump, parent: &'n mut Node<'b, T>, rest: PathIter) -> &'n mut Node<'b, T> {
+        parent.children.push(Node::leaf(arena, rest.rest()));
+        parent.children.last_mut().unwrap(/* infallible, we just pushed */)
+    }
+}
+
+/// A [`Tree`] which may be shared between connections.
+///
+/// Lookups of existing routes only take the read lock, so any number of connections may be routed in parallel. Values
+/// of routes which were not found are created without holding any lock, as doing so usually means asking the
+/// filesystem. The write lock is then only taken to insert them, unless another connection created the route in the
+/// meantime, or when routes are replaced or removed. Values created while routes were removed may predate what
+/// removed them, so they are handed out without being inserted.
+///
+/// Unlike a [`Tree`] the arena is owned, so once routes have been removed and it has grown enough the tree is copied
+/// into a fresh arena and the old one freed.
+pub struct SyncTree<T: 'static> {
+    inner: RwLock<Owned<T>>,
+    /// Bumped whenever routes are removed, only while holding the write lock
+    epoch: AtomicU64
+}
+
+/// A tree along with the arena only it is allocated in.
+struct Owned<T: 'static> {
+    // the `'static` lifetime is a lie we keep to ourselves, the tree is dropped before its arena is freed.
+    tree: ManuallyDrop<Tree<'static, T>>,
+    arena: NonNull<Bump>,
+    /// Bytes allocated in the arena when the tree was copied into it
+    compacted: usize
+}
+
+impl<T: 'static> Owned<T> {
+    fn new() -> Self {
+        let arena = NonNull::from(Box::leak(Box::new(Bump::new())));
+        // SAFETY: The arena was just allocated, and lives until the tree built in it is dropped.
+        Self { tree: ManuallyDrop::new(Tree::new(unsafe { arena.as_ref() })), arena, compacted: 0 }
+    }
+
+    /// Move to a fresh arena, in which `build` allocates the tree, freeing the old one.
+    fn reset(&mut self, build: impl FnOnce(&Tree<'static, T>, &'static Bump) -> Tree<'static, T>) {
+        let arena = NonNull::from(Box::leak(Box::new(Bump::new())));
+        // SAFETY: As in `new`.
+        let tree = build(&self.tree, unsafe { arena.as_ref() });
+
+        let old = core::mem::replace(self, Self { tree: ManuallyDrop::new(tree), arena, compacted: 0 });
+        drop(old);
+        // SAFETY: As in `new`.
+        self.compacted = unsafe { self.arena.as_ref() }.allocated_bytes();
+    }
+
+    /// Compact the arena if it has at least doubled since the tree was last copied into it.
+    fn maybe_compact(&mut self) {
+        // SAFETY: The arena lives as long as we do.
+        let allocated = unsafe { self.arena.as_ref() }.allocated_bytes();
+        if allocated > COMPACT_ABOVE && allocated > self.compacted * 2 {
+            self.reset(Tree::compact_into);
+            tracing::debug!("Compacted a route tree from {allocated} to {} bytes", self.compacted);
+        }
+    }
+}
+
+impl<T: 'static> Drop for Owned<T> {
+    fn drop(&mut self) {
+        // SAFETY: The tree is never used again, and is dropped before the arena it is allocated in. The arena was
+        // leaked from a `Box` in `new` or `reset` and nothing else refers to it, as readers only ever copy values out
+        // while holding the lock.
+        unsafe {
+            ManuallyDrop::drop(&mut self.tree);
+            drop(Box::from_raw(self.arena.as_ptr()));
+        }
+    }
+}
+
+// SAFETY: The only part of the tree which is not thread safe is the arena (`Bump` is `!Sync`), which is only touched
+// when nodes are allocated, resized or freed. This only takes place while holding the write lock. Readers only ever
+// traverse the already allocated nodes.
+unsafe impl<T: Send + Sync + 'static> Send for SyncTree<T> {}
+unsafe impl<T: Send + Sync + 'static> Sync for SyncTree<T> {}
+
+impl<T: 'static> SyncTree<T> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self { inner: RwLock::new(Owned::new()), epoch: AtomicU64::new(0) }
+    }
+
+    // The release profile aborts on panic, in debug a panic while holding the lock has already been reported, so we
+    // may as well continue serving.
+
+    #[inline]
+    #[must_use]
+    pub fn get(&self, path: PathIter) -> Option<Arc<T>> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner).tree.get(path).cloned()
+    }
+
+    /// See [`Tree::longest_match`]
+    #[inline]
+    #[must_use]
+    pub fn longest_match(&self, path: &mut PathIter) -> Option<Arc<T>> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner).tree.longest_match(path).cloned()
+    }
+
+    /// The value at `path`, created by `f` (given the whole of `path`) if there is none. `f` runs without holding the
+    /// lock, so it may be called by several connections at once for the same route, the first value inserted is kept.
+    ///
+    /// If routes were removed while `f` ran, what it saw may already be outdated, so its value is returned without
+    /// being inserted and the next lookup creates it afresh.
+    pub fn get_or_try_create<'r, F, E>(&self, path: PathIter<'r>, f: F) -> Result<Arc<T>, E>
+        where F: FnOnce(PathIter<'r>) -> Result<T, E>
+    {
+        // loaded before the lookup, so any removal after it invalidates what `f` sees. An outdated load only means the
+        // value is not kept.
+        let epoch = self.epoch.load(Ordering::Acquire);
+        if let Some(value) = self.get(path) {
+            return Ok(value);
+        }
+
+        let value = f(path)?;
+        let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        // bumps only happen under the write lock, so this sees all of them
+        if self.epoch.load(Ordering::Relaxed) != epoch {
+            return Ok(Arc::new(value));
+        }
+        let Ok(value) = inner.tree
+            .get_or_try_create(path, |_| Ok::<_, core::convert::Infallible>(value))
+            .cloned();
+        Ok(value)
+    }
+
+    /// Mark values being created as outdated, called with the write lock held.
+    #[inline]
+    fn bump_epoch(&self) {
+        self.epoch.fetch_add(1, Ordering::Release);
+    }
+
+    /// See [`Tree::replace`]
+    #[inline]
+    pub fn replace(&self, path: PathIter, value: Arc<T>) -> Option<Arc<T>> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner).tree.replace(path, value)
+    }
+
+    /// See [`Tree::remove`], compacting the arena if it has grown enough.
+    pub fn remove(&self, path: PathIter) -> Option<Arc<T>> {
+        let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        self.bump_epoch();
+        let removed = inner.tree.remove(path);
+        if removed.is_some() {
+            inner.maybe_compact();
         }
+        removed
+    }
 
-        current
+    /// See [`Tree::retain`], compacting the arena if it has grown enough.
+    #[cfg_attr(not(feature = "reload"), allow(dead_code))]
+    pub fn retain<F: FnMut(&T) -> bool>(&self, keep: F) -> usize {
+        let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        self.bump_epoch();
+        let dropped = inner.tree.retain(keep);
+        if dropped != 0 {
+            inner.maybe_compact();
+        }
+        dropped
+    }
+
+    /// Drop every route, freeing the arena along with them.
+    #[cfg_attr(not(feature = "reload"), allow(dead_code))]
+    pub fn clear(&self) {
+        let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        self.bump_epoch();
+        inner.reset(|_, arena| Tree::new(arena));
+    }
+}
+
+impl<T: 'static> Default for SyncTree<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! insert {
+        ($tree:ident, $path:literal => $value:expr) => {
+            **$tree.get_or_try_create(PathIter::new($path), |_| Ok::<_, ()>($value)).unwrap()
+        };
+    }
+
+    macro_rules! get {
+        ($tree:ident, $path:literal) => {
+            $tree.get(PathIter::new($path)).map(|__value| **__value)
+        };
+    }
+
+    #[test]
+    fn segment_boundaries() {
+        let mut tree = Tree::new_static();
+
+        assert_eq!(insert!(tree, b"a/b HTTP/1.1" => 1), 1);
+        assert_eq!(get!(tree, b"ab HTTP/1.1"), None);
+        assert_eq!(insert!(tree, b"ab HTTP/1.1" => 2), 2);
+        assert_eq!(insert!(tree, b"a HTTP/1.1" => 3), 3);
+
+        assert_eq!(get!(tree, b"a/b HTTP/1.1"), Some(1));
+        assert_eq!(get!(tree, b"ab HTTP/1.1"), Some(2));
+        assert_eq!(get!(tree, b"a HTTP/1.1"), Some(3));
+    }
+
+    #[test]
+    fn trailing_slash() {
+        let mut tree = Tree::new_static();
+
+        assert_eq!(insert!(tree, b"docs/guide/ HTTP/1.1" => 1), 1);
+        assert_eq!(get!(tree, b"docs/ HTTP/1.1"), None);
+        assert_eq!(insert!(tree, b"docs/ HTTP/1.1" => 2), 2);
+        assert_eq!(insert!(tree, b"docs HTTP/1.1" => 3), 3);
+
+        assert_eq!(get!(tree, b"docs/guide/ HTTP/1.1"), Some(1));
+        assert_eq!(get!(tree, b"docs/guide HTTP/1.1"), None);
+        assert_eq!(get!(tree, b"docs/ HTTP/1.1"), Some(2));
+        assert_eq!(get!(tree, b"docs HTTP/1.1"), Some(3));
+    }
+
+    #[test]
+    fn split_on_shorter_path() {
+        let mut tree = Tree::new_static();
+
+        assert_eq!(insert!(tree, b"hello/world.js HTTP/1.1" => 1), 1);
+        assert_eq!(get!(tree, b"hel HTTP/1.1"), None);
+        assert_eq!(insert!(tree, b"hel HTTP/1.1" => 2), 2);
+        assert_eq!(insert!(tree, b"help.txt HTTP/1.1" => 3), 3);
+        assert_eq!(insert!(tree, b"world HTTP/1.1" => 4), 4);
+
+        assert_eq!(get!(tree, b"hello/world.js HTTP/1.1"), Some(1));
+        assert_eq!(get!(tree, b"hel HTTP/1.1"), Some(2));
+        assert_eq!(get!(tree, b"help.txt HTTP/1.1"), Some(3));
+        assert_eq!(get!(tree, b"world HTTP/1.1"), Some(4));
+        assert_eq!(get!(tree, b"hello HTTP/1.1"), None);
+
+        // existing values are never recomputed
+        assert_eq!(insert!(tree, b"hel HTTP/1.1" => 5), 2);
+    }
+
+    #[test]
+    fn longest_match() {
+        let mut tree = Tree::new_static();
+        assert_eq!(insert!(tree, b"app" => 1), 1);
+        assert_eq!(insert!(tree, b"app/admin" => 2), 2);
+        assert_eq!(insert!(tree, b"assets" => 3), 3);
+
+        let mut path = PathIter::new(b"app/admin/users");
+        assert_eq!(tree.longest_match(&mut path).map(|value| **value), Some(2));
+        assert_eq!(path.rest(), b"/users");
+
+        let mut path = PathIter::new(b"app/administrator");
+        assert_eq!(tree.longest_match(&mut path).map(|value| **value), Some(1));
+        assert_eq!(path.rest(), b"/administrator");
+
+        let mut path = PathIter::new(b"app");
+        assert_eq!(tree.longest_match(&mut path).map(|value| **value), Some(1));
+        assert_eq!(path.rest(), b"");
+
+        let mut path = PathIter::new(b"application.js");
+        assert_eq!(tree.longest_match(&mut path), None);
+        assert_eq!(path.rest(), b"application.js");
+        assert_eq!(tree.longest_match(&mut PathIter::new(b"a")), None);
+    }
+
+    #[test]
+    fn remove() {
+        let mut tree = Tree::new_static();
+        assert_eq!(insert!(tree, b"a.js" => 1), 1);
+        assert_eq!(insert!(tree, b"a/b.js" => 2), 2);
+        assert_eq!(insert!(tree, b"a/c.js" => 3), 3);
+
+        assert_eq!(tree.remove(PathIter::new(b"a/b.js")).map(|value| *value), Some(2));
+        assert_eq!(tree.remove(PathIter::new(b"a/b.js")), None);
+        assert_eq!(tree.remove(PathIter::new(b"a")), None);
+        assert_eq!(get!(tree, b"a/b.js"), None);
+        assert_eq!(get!(tree, b"a/c.js"), Some(3));
+
+        // `a/` only split `b.js` from `c.js`, so it is merged back with what remains
+        assert_eq!(tree.root.children.iter().map(Node::prefix).collect::<std::vec::Vec<_>>(), [&b".js"[..], b"/c.js"]);
+
+        // recreated on the next request
+        assert_eq!(insert!(tree, b"a/b.js" => 4), 4);
+        assert_eq!(get!(tree, b"a/c.js"), Some(3));
+
+        assert_eq!(tree.retain(|value| *value == 3), 2);
+        assert_eq!(tree.root.prefix(), b"a/c.js");
+        assert_eq!(tree.retain(|_| false), 1);
+        assert_eq!(insert!(tree, b"b.js" => 5), 5);
+        assert_eq!(tree.root.prefix(), b"b.js");
+    }
+
+    #[test]
+    fn replace() {
+        let mut tree = Tree::new_static();
+        assert_eq!(tree.replace(PathIter::new(b"app"), Arc::new(1)), None);
+        assert_eq!(tree.replace(PathIter::new(b"ap"), Arc::new(2)), None);
+        assert_eq!(tree.replace(PathIter::new(b"app"), Arc::new(3)).map(|value| *value), Some(1));
+        assert_eq!(get!(tree, b"app"), Some(3));
+        assert_eq!(get!(tree, b"ap"), Some(2));
+
+        let tree = tree.compact_into(Box::leak(Box::new(Bump::new())));
+        assert_eq!(get!(tree, b"app"), Some(3));
+        assert_eq!(get!(tree, b"ap"), Some(2));
+    }
+
+    #[test]
+    fn compacts() {
+        let tree = SyncTree::new();
+        let path = |i: usize| format!("assets/index-{i:08x}.js");
+        for i in 0..4096 {
+            let hashed = path(i);
+            assert_eq!(*tree.get_or_try_create(PathIter::new(hashed.as_bytes()), |_| Ok::<_, ()>(i)).unwrap(), i);
+            if i > 0 {
+                // a rebuild replacing the hashed file of the last
+                assert!(tree.remove(PathIter::new(path(i - 1).as_bytes())).is_some());
+            }
+        }
+
+        let inner = tree.inner.read().unwrap();
+        // SAFETY: The arena lives as long as the tree.
+        assert!(unsafe { inner.arena.as_ref() }.allocated_bytes() <= COMPACT_ABOVE * 2);
+        assert_eq!(inner.tree.get(PathIter::new(path(4095).as_bytes())).map(|value| **value), Some(4095));
+        drop(inner);
+
+        tree.clear();
+        assert_eq!(tree.get(PathIter::new(path(4095).as_bytes())), None);
+    }
+
+    #[test]
+    fn creates_without_the_lock() {
+        let tree = SyncTree::new();
+        let value = tree.get_or_try_create(PathIter::new(b"a.js"), |path| {
+            assert_eq!(path.rest(), b"a.js");
+            // other connections are still routed while the value is created
+            assert!(tree.inner.try_read().is_ok());
+            // and may create the same route in the meantime, which is then kept
+            let created = tree.get_or_try_create(PathIter::new(b"a.js"), |_| Ok::<_, ()>(1)).unwrap();
+            Ok::<_, ()>(*created + 1)
+        }).unwrap();
+        assert_eq!(*value, 1);
+
+        assert_eq!(tree.get_or_try_create(PathIter::new(b"b.js"), |_| Err::<usize, _>("missing")), Err("missing"));
+        assert!(tree.get(PathIter::new(b"b.js")).is_none());
+    }
+
+    #[test]
+    fn removals_outdate_created_values() {
+        let tree = SyncTree::new();
+        let stale = tree.get_or_try_create(PathIter::new(b"a.js"), |_| {
+            // the watcher dropping changed routes after the value was read from the filesystem
+            tree.retain(|_| true);
+            Ok::<_, ()>(1)
+        }).unwrap();
+        assert_eq!(*stale, 1);
+        assert!(tree.get(PathIter::new(b"a.js")).is_none());
+
+        let stale = tree.get_or_try_create(PathIter::new(b"a.js"), |_| {
+            tree.clear();
+            Ok::<_, ()>(2)
+        }).unwrap();
+        assert_eq!(*stale, 2);
+        assert!(tree.get(PathIter::new(b"a.js")).is_none());
+
+        assert_eq!(*tree.get_or_try_create(PathIter::new(b"a.js"), |_| Ok::<_, ()>(3)).unwrap(), 3);
+        assert_eq!(tree.get(PathIter::new(b"a.js")).map(|value| *value), Some(3));
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let tree: &'static SyncTree<usize> = Box::leak(Box::new(SyncTree::new()));
+        let paths = ["a.js", "b.js", "a/b.js", "a/c.js", "abc.js", "b/a.js"];
+
+        let handles = (0..8).map(|_| std::thread::spawn(move || {
+            for (i, path) in paths.iter().enumerate() {
+                let value = tree.get_or_try_create(PathIter::new(path.as_bytes()), |_| Ok::<_, ()>(i)).unwrap();
+                assert_eq!(*value, i);
+            }
+        })).collect::<std::vec::Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
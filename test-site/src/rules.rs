@@ -0,0 +1,129 @@
+//! Headers attached to the files we serve by their path or MIME type, from the `[[headers]]` tables of the config file.
+//!
+//! Rules are applied in order, a header set by a rule replaces any of the same name set before it, including our own
+//! `Cache-Control`.
+use crate::conf::HeaderRuleConf;
+use crate::glob::Glob;
+use crate::parse::{percent_decode, Request};
+use crate::response::{HeaderValue, Response};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Common sets of headers, so that rules need not spell them out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Allow service workers to control the whole origin, whatever directory they are served from
+    ServiceWorker,
+    /// `Cross-Origin-Opener-Policy` and `Cross-Origin-Embedder-Policy`, required for `SharedArrayBuffer` and
+    /// high resolution timers
+    CrossOriginIsolated,
+    /// Only load resources from our own origin
+    Csp,
+    /// Cache for a year without revalidating, for fingerprinted assets
+    Immutable,
+    /// Never cache
+    NoStore
+}
+
+impl Preset {
+    #[must_use]
+    pub const fn headers(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::ServiceWorker => &[("Service-Worker-Allowed", "/")],
+            Self::CrossOriginIsolated => &[
+                ("Cross-Origin-Opener-Policy", "same-origin"),
+                ("Cross-Origin-Embedder-Policy", "require-corp")
+            ],
+            Self::Csp => &[("Content-Security-Policy", "default-src 'self'")],
+            Self::Immutable => &[("Cache-Control", "public, max-age=31536000, immutable")],
+            Self::NoStore => &[("Cache-Control", "no-store")]
+        }
+    }
+}
+
+struct Rule {
+    path: Option<&'static Glob>,
+    mime: Option<&'static Glob>,
+    headers: Vec<(&'static str, HeaderValue)>
+}
+
+impl Rule {
+    #[inline]
+    fn matches(&self, path: &str, mime: &str) -> bool {
+        self.path.is_none_or(|glob| glob.matches(path)) && self.mime.is_none_or(|glob| glob.matches(mime))
+    }
+}
+
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    #[must_use]
+    pub fn new(conf: &'static [HeaderRuleConf]) -> Self {
+        Self(conf.iter().map(|rule| Rule {
+            path: rule.path.as_ref(),
+            mime: rule.mime.as_ref(),
+            headers: rule.presets.iter()
+                .flat_map(Preset::headers)
+                .map(|(name, value)| (*name, Cow::Borrowed(*value)))
+                .chain(rule.set.iter().map(|(name, value)| (name.as_str(), Cow::Owned(value.clone()))))
+                .collect()
+        }).collect())
+    }
+
+    /// Attach the headers of every rule matching the request path of `req` and `mime` to `res`.
+    pub fn apply(&self, req: &Request, mime: &str, res: Response) -> Response {
+        if self.0.is_empty() {
+            return res;
+        }
+
+        let path = req.origin_path();
+        let path = percent_decode(path).unwrap_or(Cow::Borrowed(path));
+        let path = String::from_utf8_lossy(&path);
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+
+        self.0.iter()
+            .filter(|rule| rule.matches(&path, mime))
+            .flat_map(|rule| &rule.headers)
+            .fold(res, |res, (name, value)| res.set_header(name, value.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::{Body, Status};
+
+    #[test]
+    fn applies_in_order() {
+        #[derive(Deserialize)]
+        struct File {
+            headers: Vec<HeaderRuleConf>
+        }
+        let file: File = toml::from_str(
+            "[[headers]]\npath = \"/**/sw.js\"\npresets = [\"service-worker\"]\n\n\
+            [[headers]]\nmime = \"text/html\"\npresets = [\"cross-origin-isolated\", \"no-store\"]\n\n\
+            [[headers]]\npath = \"/assets/**\"\npresets = [\"immutable\"]\nset = { X-Asset = \"yes\" }"
+        ).unwrap();
+        let rules = Rules::new(file.headers.leak());
+
+        let respond = |target: &'static str, mime: &str| {
+            let raw = format!("GET {target} HTTP/1.1\r\n\r\n").leak();
+            let res = Response::new(Status::OK, Body::Empty).header("Cache-Control", "no-cache");
+            rules.apply(&Request::parse(raw.as_bytes()).unwrap(), mime, res).headers
+        };
+
+        let sw = respond("/app/sw.js?v=2", "text/javascript");
+        assert!(sw.contains(&("Service-Worker-Allowed", Cow::Borrowed("/"))));
+        assert!(sw.contains(&("Cache-Control", Cow::Borrowed("no-cache"))));
+
+        let page = respond("/", "text/html; charset=utf-8");
+        assert!(page.contains(&("Cross-Origin-Embedder-Policy", Cow::Borrowed("require-corp"))));
+        assert_eq!(page.iter().filter(|(name, _)| *name == "Cache-Control").count(), 1);
+        assert!(page.contains(&("Cache-Control", Cow::Borrowed("no-store"))));
+
+        let asset = respond("/assets/main%20v2.css", "text/css");
+        assert!(asset.contains(&("Cache-Control", Cow::Borrowed("public, max-age=31536000, immutable"))));
+        assert!(asset.contains(&("X-Asset", Cow::Borrowed("yes"))));
+    }
+}
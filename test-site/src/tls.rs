@@ -0,0 +1,236 @@
+//! HTTPS through rustls, with a certificate supplied by the user or issued by a generated development CA.
+//!
+//! Service workers only register in secure contexts, so anything but `localhost` needs HTTPS. Generated certificates
+//! are issued by a CA kept in `tls.cert_dir`, which only needs to be trusted once, the leaf certificate is reissued
+//! whenever the configured names change or it nears expiry.
+use crate::conf::TlsConf;
+use crate::parse::Request;
+use crate::response::{Response, Status};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair, KeyUsagePurpose
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use rustls::ServerConfig;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::TlsAcceptor;
+use tracing::{instrument, info, warn, Level};
+
+const CA_NAME: &str = "test-site development CA";
+/// How long generated leaf certificates are valid for
+const LEAF_VALIDITY: Duration = Duration::days(365);
+/// Leaf certificates are reissued once they have less than this left
+const LEAF_RENEW: Duration = Duration::days(30);
+
+macro_rules! tls_err {
+    ($ctx:literal, $err:expr) => {
+        io::Error::other(format!(concat!($ctx, ": {}"), $err))
+    };
+}
+
+/// Build the acceptor for HTTPS connections. `host` is the address we're bound to, which is included in generated
+/// certificates so that other devices on the network may connect to it directly.
+#[instrument(name = "tls", skip_all, err(Debug, level = Level::DEBUG), level = Level::DEBUG)]
+pub fn acceptor(conf: &TlsConf, host: IpAddr) -> io::Result<TlsAcceptor> {
+    let (certs, key) = match (&conf.cert, &conf.key) {
+        (Some(cert), Some(key)) => (load_certs(cert)?, load_key(key)?),
+        _ => generated(conf, host)?
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut server = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| tls_err!("Unsupported TLS protocol versions", err))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| tls_err!("Invalid TLS certificate or key", err))?;
+    server.alpn_protocols = vec![
+        #[cfg(feature = "http2")]
+        b"h2".to_vec(),
+        b"http/1.1".to_vec()
+    ];
+
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| tls_err!("Could not read the TLS certificate", err))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("`{}` does not contain any PEM certificates", path.display())
+        ));
+    }
+    Ok(certs)
+}
+
+#[inline]
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|err| tls_err!("Could not read the TLS private key", err))
+}
+
+/// Write a file only we may read, as it may hold a private key.
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)] {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// The parameters of the development CA. These are always the same, so the CA can sign with just its key, and
+/// certificates issued by an earlier run chain to the same (trusted) root.
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.distinguished_name.push(DnType::OrganizationName, "test-site");
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    params
+}
+
+/// Localhost, the address we're bound to (if it's specific) and any configured names, without duplicates.
+fn names(conf: &TlsConf, host: IpAddr) -> Vec<String> {
+    let mut names = vec!["localhost".to_owned(), "127.0.0.1".to_owned(), "::1".to_owned()];
+    if !host.is_unspecified() && !host.is_loopback() {
+        names.push(host.to_string());
+    }
+    for name in &conf.names {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+fn generated(conf: &TlsConf, host: IpAddr) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let dir = conf.cert_dir();
+    fs::create_dir_all(dir)?;
+    let (ca_path, ca_key_path) = (dir.join("ca.pem"), dir.join("ca-key.pem"));
+    let (leaf_path, leaf_key_path, meta_path) = (dir.join("cert.pem"), dir.join("key.pem"), dir.join("cert.meta"));
+
+    let names = names(conf, host);
+    let now = OffsetDateTime::now_utc();
+
+    // the names and expiry of the cached leaf certificate, so we needn't parse it to know whether it is still usable
+    let meta = fs::read_to_string(&meta_path).unwrap_or_default();
+    let fresh = meta.lines().next() == Some(names.join(",").as_str())
+        && meta.lines().nth(1)
+            .and_then(|expiry| expiry.parse::<i64>().ok())
+            .is_some_and(|expiry| expiry > (now + LEAF_RENEW).unix_timestamp())
+        && leaf_path.is_file()
+        && leaf_key_path.is_file()
+        && ca_path.is_file();
+
+    if fresh {
+        info!("Using the cached certificate in {}", dir.display());
+        return Ok((load_certs(&leaf_path)?, load_key(&leaf_key_path)?));
+    }
+
+    let ca_key = match fs::read_to_string(&ca_key_path) {
+        Ok(pem) if ca_path.is_file() => KeyPair::from_pem(&pem).map_err(|err| tls_err!("Invalid CA key", err))?,
+        _ => {
+            let key = KeyPair::generate().map_err(|err| tls_err!("Could not generate the CA key", err))?;
+            let ca = ca_params().self_signed(&key).map_err(|err| tls_err!("Could not generate the CA", err))?;
+            write_private(&ca_key_path, &key.serialize_pem())?;
+            fs::write(&ca_path, ca.pem())?;
+            warn!(
+                "Generated a development CA at {}, trust it in your browser or operating system to avoid certificate \
+                warnings. Keep {} private.",
+                ca_path.display(), ca_key_path.display()
+            );
+            key
+        }
+    };
+    let issuer = Issuer::new(ca_params(), ca_key);
+
+    let mut params = CertificateParams::new(names.clone())
+        .map_err(|err| tls_err!("Invalid name for the TLS certificate", err))?;
+    params.distinguished_name.push(DnType::CommonName, names[0].as_str());
+    params.use_authority_key_identifier_extension = true;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + LEAF_VALIDITY;
+
+    let key = KeyPair::generate().map_err(|err| tls_err!("Could not generate the certificate key", err))?;
+    let leaf = params.signed_by(&key, &issuer).map_err(|err| tls_err!("Could not issue the certificate", err))?;
+
+    write_private(&leaf_key_path, &key.serialize_pem())?;
+    fs::write(&leaf_path, leaf.pem())?;
+    fs::write(&meta_path, format!("{}\n{}\n", names.join(","), params.not_after.unix_timestamp()))?;
+    info!("Issued a certificate for {} in {}", names.join(", "), dir.display());
+
+    Ok((vec![leaf.der().clone()], PrivateKeyDer::Pkcs8(key.serialize_der().into())))
+}
+
+/// Redirect a plain HTTP request to the same host and target over HTTPS on `port`.
+pub fn redirect(req: &Request, port: u16) -> Response {
+    // the port is dropped, as we're redirecting to our own
+    let Some(hostname) = req.hostname() else {
+        return Response::status(Status::BAD_REQUEST);
+    };
+    let target = String::from_utf8_lossy(req.origin_form());
+    let location = match port {
+        443 => format!("https://{hostname}{target}"),
+        port => format!("https://{hostname}:{port}{target}")
+    };
+
+    // temporary, as a permanent redirect would be remembered by the browser long after TLS is turned off
+    Response::status(Status::TEMPORARY_REDIRECT).header("Location", location)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(raw: &'static [u8], port: u16) -> Option<String> {
+        let req = Request::parse(raw).unwrap();
+        let res = redirect(&req, port);
+        res.headers.iter().find(|(name, _)| *name == "Location").map(|(_, value)| value.to_string())
+    }
+
+    #[test]
+    fn redirects() {
+        assert_eq!(
+            location(b"GET /a/b.js?v=1 HTTP/1.1\r\nHost: dev.test:8080\r\n\r\n", 8443).as_deref(),
+            Some("https://dev.test:8443/a/b.js?v=1")
+        );
+        assert_eq!(location(b"GET / HTTP/1.1\r\nHost: dev.test\r\n\r\n", 443).as_deref(), Some("https://dev.test/"));
+        assert_eq!(location(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n", 443).as_deref(), Some("https://[::1]/"));
+        assert_eq!(location(b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n", 443).as_deref(), Some("https://[::1]/"));
+        assert_eq!(location(b"GET / HTTP/1.1\r\n\r\n", 443), None);
+        assert_eq!(location(b"GET / HTTP/1.1\r\nHost: a/b@c\r\n\r\n", 443), None);
+    }
+
+    #[test]
+    fn issues_from_cached_ca() {
+        let dir = std::env::temp_dir().join(format!("test-site-certs-{}", std::process::id()));
+        let conf = TlsConf { cert_dir: Some(dir.clone()), names: vec!["dev.test".to_owned()], ..TlsConf::default() };
+
+        generated(&conf, IpAddr::from([127, 0, 0, 1])).unwrap();
+        let ca = fs::read(dir.join("ca.pem")).unwrap();
+        let leaf = fs::read(dir.join("cert.pem")).unwrap();
+
+        // cached while the names are unchanged
+        generated(&conf, IpAddr::from([127, 0, 0, 1])).unwrap();
+        assert_eq!(fs::read(dir.join("cert.pem")).unwrap(), leaf);
+
+        // reissued by the same CA once they change
+        let conf = TlsConf { names: vec!["other.test".to_owned()], ..conf };
+        generated(&conf, IpAddr::from([127, 0, 0, 1])).unwrap();
+        assert_ne!(fs::read(dir.join("cert.pem")).unwrap(), leaf);
+        assert_eq!(fs::read(dir.join("ca.pem")).unwrap(), ca);
+
+        acceptor(&conf, IpAddr::from([127, 0, 0, 1])).unwrap();
+        let _ = fs::remove_dir_all(dir);
+    }
+}
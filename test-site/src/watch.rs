@@ -0,0 +1,263 @@
+//! Watching the served files for changes, with the `reload` feature.
+//!
+//! A single thread receives the events of the platform's watcher (inotify, FSEvents, kqueue, ...) and waits for them to
+//! settle, as a build touches many files at once and editors save in several steps. It then refreshes the contents of
+//! the files we have loaded, and hands the changes on so that routes to files which were created, removed or renamed
+//! are invalidated. Requests never touch the filesystem to find out whether a file changed.
+use crate::lazy_file::LazyFile;
+use notify::event::{EventKind, ModifyKind};
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{trace, debug, info, warn};
+
+/// How long events must stop arriving for before we act on them
+const DEBOUNCE: Duration = Duration::from_millis(50);
+/// The longest we wait for events to stop arriving, so a file being written continuously is still picked up
+const MAX_DELAY: Duration = Duration::from_millis(500);
+
+/// The files we have loaded by their absolute path, several may share a path (the index is also routable).
+static FILES: LazyLock<Mutex<HashMap<PathBuf, Vec<Weak<LazyFile>>>>> = LazyLock::new(Mutex::default);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    /// The contents (or metadata) of the file changed
+    Contents,
+    /// The path was created, removed or renamed
+    Structure
+}
+
+/// The changes of a settled batch of events.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// What changed at each absolute path
+    pub paths: BTreeMap<PathBuf, Change>,
+    /// Events were lost, so anything may have changed
+    pub rescan: bool
+}
+
+impl Changes {
+    fn add(&mut self, event: notify::Result<notify::Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("The watcher reported an error, treating everything as changed: {err}");
+                self.rescan = true;
+                return;
+            }
+        };
+        if event.need_rescan() {
+            self.rescan = true;
+        }
+
+        let change = match event.kind {
+            EventKind::Access(_) => return,
+            EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => Change::Structure,
+            EventKind::Modify(_) | EventKind::Any | EventKind::Other => Change::Contents
+        };
+        for path in event.paths {
+            let existing = self.paths.entry(path).or_insert(change);
+            *existing = core::cmp::max(*existing, change);
+        }
+    }
+
+    /// Paths which were created, removed or renamed
+    #[inline]
+    pub fn structural(&self) -> impl Iterator<Item = &Path> {
+        self.paths.iter().filter(|(_, change)| **change == Change::Structure).map(|(path, _)| path.as_path())
+    }
+}
+
+#[inline]
+pub fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Have `file` refreshed whenever `path` changes.
+pub fn register(path: &Path, file: &Arc<LazyFile>) {
+    let mut files = FILES.lock().unwrap_or_else(PoisonError::into_inner);
+    let registered = files.entry(absolute(path)).or_default();
+    registered.retain(|file| file.strong_count() != 0);
+    registered.push(Arc::downgrade(file));
+}
+
+/// Refresh the contents of every loaded file which changed.
+fn refresh(changes: &Changes) {
+    // collected first, so that the lock is not held while reading files
+    let files = {
+        let mut files = FILES.lock().unwrap_or_else(PoisonError::into_inner);
+        files.retain(|_, registered| {
+            registered.retain(|file| file.strong_count() != 0);
+            !registered.is_empty()
+        });
+        files.iter()
+            .filter(|(path, _)| changes.rescan || changes.paths.contains_key(*path))
+            .flat_map(|(path, registered)| registered.iter().filter_map(Weak::upgrade).map(|file| (path.clone(), file)))
+            .collect::<Vec<_>>()
+    };
+
+    for (path, file) in files {
+        match file.refresh() {
+            Ok(true) => info!("Reloaded {}", path.display()),
+            Ok(false) => trace!("{} is unchanged", path.display()),
+            // removals are left to invalidating the routes
+            Err(err) if err.kind() == io::ErrorKind::NotFound => debug!("{} was removed", path.display()),
+            Err(err) => warn!("Could not reload {}: {err:?}", path.display())
+        }
+    }
+}
+
+/// Wait for the next batch of events to settle, `None` once the watcher has stopped.
+fn settle(events: &Receiver<notify::Result<notify::Event>>) -> Option<Changes> {
+    let mut changes = Changes::default();
+    changes.add(events.recv().ok()?);
+
+    let deadline = Instant::now() + MAX_DELAY;
+    loop {
+        match events.recv_timeout(core::cmp::min(DEBOUNCE, deadline.saturating_duration_since(Instant::now()))) {
+            Ok(event) => changes.add(event),
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return Some(changes)
+        }
+    }
+}
+
+/// The directories containing the recursively watched `roots`, which are not already watched themselves.
+fn parents(roots: &[(PathBuf, RecursiveMode)]) -> Vec<PathBuf> {
+    let mut parents: Vec<PathBuf> = Vec::new();
+    for (root, mode) in roots {
+        let Some(parent) = root.parent().filter(|_| *mode == RecursiveMode::Recursive) else { continue };
+        let watched = parents.iter().any(|other| other == parent) || roots.iter().any(|(other, mode)| {
+            other == parent || (*mode == RecursiveMode::Recursive && parent.starts_with(other))
+        });
+        if !watched {
+            parents.push(parent.to_path_buf());
+        }
+    }
+    parents
+}
+
+/// Watch the roots among `changes` again, as a watch does not survive its directory being removed or renamed.
+fn rewatch(watcher: &mut impl Watcher, roots: &[(PathBuf, RecursiveMode)], changes: &Changes) {
+    for (root, mode) in roots {
+        if changes.paths.get(root) != Some(&Change::Structure) {
+            continue;
+        }
+        // a renamed root may still be watched where it went
+        let _ = watcher.unwatch(root);
+        match watcher.watch(root, *mode) {
+            Ok(()) => info!("{} was created again, watching it for changes", root.display()),
+            Err(_) if !root.exists() => warn!(
+                "{} was removed, changes within it are picked up again once it is created", root.display()
+            ),
+            Err(err) => warn!("Could not watch {} again, changes within it are missed: {err}", root.display())
+        }
+    }
+}
+
+/// Watch `roots`, recursively if paired with `true`, calling `on_change` with each settled batch of changes once the
+/// loaded files have been refreshed.
+///
+/// Builds often remove the dist directory and create it afresh, so the directories containing the recursively watched
+/// roots are watched as well, for the roots to be watched again once they are created.
+pub fn spawn<F>(roots: Vec<(PathBuf, bool)>, on_change: F) -> io::Result<()>
+    where F: Fn(&Changes) + Send + 'static
+{
+    let (tx, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(io::Error::other)?;
+    let roots = roots.into_iter()
+        .map(|(root, recursive)| {
+            (absolute(&root), if recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive })
+        })
+        .collect::<Vec<_>>();
+    for (root, mode) in &roots {
+        watcher.watch(root, *mode)
+            .map_err(|err| io::Error::other(format!("Could not watch `{}`: {err}", root.display())))?;
+        debug!("Watching {} for changes", root.display());
+    }
+    for parent in parents(&roots) {
+        match watcher.watch(&parent, RecursiveMode::NonRecursive) {
+            Ok(()) => debug!("Watching {} for the directories within it being created again", parent.display()),
+            Err(err) => warn!(
+                "Could not watch {}, reloading stops if a directory within it is removed: {err}", parent.display()
+            )
+        }
+    }
+
+    thread::Builder::new().name("watcher".to_owned()).spawn(move || {
+        // dropping the watcher stops it, so it lives as long as the thread
+        let mut watcher = watcher;
+        while let Some(changes) = settle(&events) {
+            trace!("{} paths changed, rescan: {}", changes.paths.len(), changes.rescan);
+            rewatch(&mut watcher, &roots, &changes);
+            refresh(&changes);
+            on_change(&changes);
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, Event};
+
+    #[test]
+    fn settles() {
+        let (tx, events) = mpsc::channel();
+        let modify = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)));
+        tx.send(Ok(modify.clone().add_path(PathBuf::from("/dist/a.js")))).unwrap();
+        tx.send(Ok(modify.add_path(PathBuf::from("/dist/b.js")))).unwrap();
+        tx.send(Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("/dist/b.js")))).unwrap();
+        let access = Event::new(EventKind::Access(notify::event::AccessKind::Any));
+        tx.send(Ok(access.add_path(PathBuf::from("/x")))).unwrap();
+
+        let changes = settle(&events).unwrap();
+        assert_eq!(changes.paths.len(), 2);
+        assert_eq!(changes.paths[Path::new("/dist/a.js")], Change::Contents);
+        assert_eq!(changes.structural().collect::<Vec<_>>(), [Path::new("/dist/b.js")]);
+        assert!(!changes.rescan);
+
+        drop(tx);
+        assert!(settle(&events).is_none());
+    }
+
+    #[test]
+    fn rewatches_recreated_roots() {
+        let base = std::env::temp_dir().join(format!("test-site-rewatch-{}", std::process::id()));
+        let dist = base.join("dist");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&dist).unwrap();
+        assert_eq!(parents(&[(dist.clone(), RecursiveMode::Recursive)]), [base.as_path()]);
+        assert!(parents(&[(base.clone(), RecursiveMode::Recursive), (dist.clone(), RecursiveMode::Recursive)])
+            .iter()
+            .all(|parent| *parent != base));
+
+        let (tx, changed) = mpsc::channel();
+        spawn(vec![(dist.clone(), true)], move |changes| {
+            let _ = tx.send(changes.paths.keys().cloned().collect::<Vec<_>>());
+        }).unwrap();
+        let expect = |path: &Path| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while let Ok(paths) = changed.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                if paths.iter().any(|changed| changed == path) {
+                    return;
+                }
+            }
+            panic!("{} was not reported as changed", path.display());
+        };
+
+        // as a build would
+        std::fs::remove_dir_all(&dist).unwrap();
+        expect(&dist);
+        std::fs::create_dir(&dist).unwrap();
+        expect(&dist);
+        std::fs::write(dist.join("late.js"), "late").unwrap();
+        expect(&dist.join("late.js"));
+        let _ = std::fs::remove_dir_all(base);
+    }
+}